@@ -0,0 +1,109 @@
+use bigdecimal::{BigDecimal, FromPrimitive, RoundingMode, Zero};
+use cached::proc_macro::cached;
+use cached::{Cached, SizedCache};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use orderbook::{group, OrderBook, OrderType};
+use prost::Message;
+use std::cmp::max;
+use std::str::FromStr;
+use stock_messages::stock_messages::SnapshotMessage;
+
+// The cached, string-keyed grouping `group` replaced, kept as the baseline.
+#[cached(
+    type = "SizedCache<String, i64>",
+    create = "{ SizedCache::with_size(1000000) }",
+    convert = r#"{ format!("{}", value) }"#
+)]
+fn cached_value_to_scale(value: f64) -> i64 {
+    let decimal = BigDecimal::from_str(&value.to_string()).unwrap_or_default();
+    let (_, decimal_places) = decimal.normalized().as_bigint_and_exponent();
+    max(0, decimal_places)
+}
+
+#[cached(
+    type = "SizedCache<String, BigDecimal>",
+    create = "{ SizedCache::with_size(1000000) }",
+    convert = r#"{ format!("{}{}{}", decimal, group_size, group_lower) }"#
+)]
+fn cached_group(decimal: BigDecimal, group_size: f64, group_lower: bool) -> BigDecimal {
+    let scale = cached_value_to_scale(group_size);
+    let group_decimal = BigDecimal::from_str(&group_size.to_string()).unwrap_or_default();
+    let decimal = decimal.with_scale_round(scale + 4, RoundingMode::HalfUp);
+    let rounding_mode: RoundingMode = if group_lower { RoundingMode::Floor } else { RoundingMode::Ceiling };
+    let div = (decimal.clone() / group_decimal.clone()).with_scale_round(0, RoundingMode::Floor);
+    let calculated = (div * group_decimal.clone()).with_scale_round(scale, RoundingMode::Floor);
+    if calculated == decimal {
+        return calculated;
+    }
+    (calculated + if group_lower { BigDecimal::zero() } else { group_decimal }).with_scale_round(scale, rounding_mode)
+}
+
+fn load_book() -> OrderBook {
+    let bytes = std::fs::read("snapshots/Binance:BTC_USDT").unwrap();
+    OrderBook::from(SnapshotMessage::decode(bytes).unwrap())
+}
+
+// Prices of a stream of level updates, mostly new to the book.
+fn update_prices(count: usize) -> Vec<BigDecimal> {
+    (0..count)
+        .map(|index| BigDecimal::from_f64(9000.0 + index as f64 * 0.01).unwrap())
+        .collect()
+}
+
+fn bench_group(c: &mut Criterion) {
+    let prices = update_prices(10_000);
+    let mut benchmarks = c.benchmark_group("group 10k update prices");
+    benchmarks.bench_function("cached, warm", |b| {
+        b.iter(|| {
+            for price in prices.iter() {
+                black_box(cached_group(price.clone(), 0.5, true));
+            }
+        })
+    });
+    // every update price is new to the cache
+    benchmarks.bench_function("cached, cold", |b| {
+        b.iter_batched(
+            || CACHED_GROUP.lock().unwrap().cache_clear(),
+            |_| {
+                for price in prices.iter() {
+                    black_box(cached_group(price.clone(), 0.5, true));
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    benchmarks.bench_function("arithmetic", |b| {
+        b.iter(|| {
+            for price in prices.iter() {
+                black_box(group(price.clone(), 0.5, true));
+            }
+        })
+    });
+    benchmarks.finish();
+}
+
+fn bench_update_path(c: &mut Criterion) {
+    let book = load_book();
+    let sequence = book.sequence;
+    let prices = update_prices(1_000);
+    c.bench_function("add_level 1k updates, group size 0.5", |b| {
+        b.iter_batched(
+            || {
+                let mut book = book.clone();
+                book.set_group_size(0.5);
+                book
+            },
+            |mut book| {
+                for (index, price) in prices.iter().enumerate() {
+                    book.add_level_decimal(OrderType::Bid, price.clone(), BigDecimal::from(1), sequence + 1 + index as u64)
+                        .unwrap();
+                }
+                book
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_group, bench_update_path);
+criterion_main!(benches);
@@ -0,0 +1,191 @@
+pub mod audit {
+    use crate::book::book::{Level, OrderBook, OrderType, Price, Size, Value};
+    use crate::grouping::grouping::GroupingStrategy;
+    use bigdecimal::BigDecimal;
+    use num_traits::identities::Zero;
+    use std::collections::BTreeMap;
+    use std::fmt;
+    use std::ops::Mul;
+
+    /// The incrementally maintained value that diverged from its recomputation.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum AuditField {
+        BidsTotal,
+        AsksTotal,
+        BidsValueTotal,
+        AsksValueTotal,
+        /// `Level::value` is not `price * size`, or the level is keyed under another price.
+        LevelValue(OrderType, Price),
+        GroupedBid(Price),
+        GroupedAsk(Price),
+        ViewGroupedBid(String, Price),
+        ViewGroupedAsk(String, Price),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Divergence {
+        pub field: AuditField,
+        /// Value recomputed from the levels.
+        pub expected: BigDecimal,
+        /// Value held by the book; missing grouped entries count as zero.
+        pub actual: BigDecimal,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct AuditReport {
+        pub instrument: String,
+        pub sequence: u64,
+        pub divergences: Vec<Divergence>,
+    }
+
+    impl AuditReport {
+        pub fn is_clean(&self) -> bool {
+            self.divergences.is_empty()
+        }
+    }
+
+    impl fmt::Display for AuditReport {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "audit {} at {}: {} divergences", self.instrument, self.sequence, self.divergences.len())?;
+            for divergence in self.divergences.iter() {
+                write!(f, "\n  {:?} expected {} actual {}", divergence.field, divergence.expected, divergence.actual)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn compare(divergences: &mut Vec<Divergence>, field: AuditField, expected: BigDecimal, actual: &BigDecimal) {
+        if &expected != actual {
+            divergences.push(Divergence {
+                field,
+                expected,
+                actual: actual.clone(),
+            });
+        }
+    }
+
+    fn audit_side(
+        divergences: &mut Vec<Divergence>,
+        order_type: OrderType,
+        levels: &BTreeMap<Price, Level>,
+        grouping: &GroupingStrategy,
+    ) -> (Size, Value, BTreeMap<Price, Size>) {
+        let group_lower = order_type == OrderType::Bid;
+        let mut total = BigDecimal::zero();
+        let mut value_total = BigDecimal::zero();
+        let mut grouped: BTreeMap<Price, Size> = BTreeMap::new();
+        for (price, level) in levels.iter() {
+            let value = price.mul(&level.size);
+            if &level.price != price || level.value != value {
+                divergences.push(Divergence {
+                    field: AuditField::LevelValue(order_type, price.clone()),
+                    expected: value.clone(),
+                    actual: level.value.clone(),
+                });
+            }
+            total += &level.size;
+            value_total += value;
+            *grouped.entry(grouping.bucket(price, group_lower)).or_default() += &level.size;
+        }
+        (total, value_total, grouped)
+    }
+
+    fn compare_groupings<F>(divergences: &mut Vec<Divergence>, expected: BTreeMap<Price, Size>, actual: &BTreeMap<Price, Size>, field: F)
+    where
+        F: Fn(Price) -> AuditField,
+    {
+        let zero = BigDecimal::zero();
+        for (price, size) in actual.iter() {
+            if !expected.contains_key(price) {
+                compare(divergences, field(price.clone()), zero.clone(), size);
+            }
+        }
+        for (price, size) in expected.into_iter() {
+            let actual_size = actual.get(&price).unwrap_or(&zero);
+            compare(divergences, field(price), size, actual_size);
+        }
+    }
+
+    impl OrderBook {
+        /// Recomputes the totals, value totals and groupings from the levels and
+        /// reports every place where the incrementally maintained copy differs.
+        pub fn audit(&self) -> AuditReport {
+            let mut divergences = Vec::new();
+            let grouping = self.grouping_strategy();
+            let (bids_total, bids_value_total, grouped_bids) = audit_side(&mut divergences, OrderType::Bid, &self.bids, &grouping);
+            let (asks_total, asks_value_total, grouped_asks) = audit_side(&mut divergences, OrderType::Ask, &self.asks, &grouping);
+            compare(&mut divergences, AuditField::BidsTotal, bids_total, &self.bids_total);
+            compare(&mut divergences, AuditField::BidsValueTotal, bids_value_total, &self.bids_value_total);
+            compare(&mut divergences, AuditField::AsksTotal, asks_total, &self.asks_total);
+            compare(&mut divergences, AuditField::AsksValueTotal, asks_value_total, &self.asks_value_total);
+            compare_groupings(&mut divergences, grouped_bids, &self.grouped_bids, AuditField::GroupedBid);
+            compare_groupings(&mut divergences, grouped_asks, &self.grouped_asks, AuditField::GroupedAsk);
+            for (name, view) in self.grouping_views().iter() {
+                // level values were already checked above
+                let mut ignored = Vec::new();
+                let (_, _, grouped_bids) = audit_side(&mut ignored, OrderType::Bid, &self.bids, &view.strategy);
+                let (_, _, grouped_asks) = audit_side(&mut ignored, OrderType::Ask, &self.asks, &view.strategy);
+                compare_groupings(&mut divergences, grouped_bids, &view.grouped_bids, |price| AuditField::ViewGroupedBid(name.clone(), price));
+                compare_groupings(&mut divergences, grouped_asks, &view.grouped_asks, |price| AuditField::ViewGroupedAsk(name.clone(), price));
+            }
+            AuditReport {
+                instrument: self.instrument.clone(),
+                sequence: self.sequence,
+                divergences,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::audit::AuditField;
+    use crate::book::book::{OrderBook, OrderType};
+    use bigdecimal::BigDecimal;
+    use prost::Message;
+    use std::str::FromStr;
+    use stock_messages::stock_messages::SnapshotMessage;
+
+    fn decimal(value: &str) -> BigDecimal {
+        BigDecimal::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn test_incremental_totals_are_clean() {
+        let bytes = std::fs::read("snapshots/Binance:BTC_USDT").unwrap();
+        let mut book = OrderBook::from(SnapshotMessage::decode(bytes).unwrap());
+        book.set_group_size(10.0);
+        book.set_audit_mode(true);
+        let sequence = book.sequence;
+        book.add_level_str(OrderType::Bid, "9015.85", "1.5", sequence + 1).unwrap();
+        book.add_level_str(OrderType::Bid, "9015.85", "0.5", sequence + 2).unwrap();
+        book.add_level_str(OrderType::Ask, "9020", "2", sequence + 3).unwrap();
+        book.remove_level_str(OrderType::Ask, "9017.78", sequence + 4).unwrap();
+        assert!(book.audit().is_clean());
+        assert!(book.drain_audit_reports().is_empty());
+    }
+
+    #[test]
+    fn test_divergences_are_reported() {
+        let mut book = OrderBook::new("instrument", 0);
+        book.add_level_str(OrderType::Bid, "100.5", "2", 1).unwrap();
+        book.add_level_str(OrderType::Bid, "101", "1", 2).unwrap();
+        book.bids_total = decimal("4");
+        book.grouped_bids.insert(decimal("90"), decimal("1"));
+
+        let report = book.audit();
+        assert_eq!(report.sequence, 2);
+        assert_eq!(report.divergences.len(), 2);
+        assert_eq!(report.divergences[0].field, AuditField::BidsTotal);
+        assert_eq!(report.divergences[0].expected, decimal("3"));
+        assert_eq!(report.divergences[0].actual, decimal("4"));
+        assert_eq!(report.divergences[1].field, AuditField::GroupedBid(decimal("90")));
+        assert_eq!(report.divergences[1].expected, decimal("0"));
+
+        book.set_audit_mode(true);
+        book.add_level_str(OrderType::Ask, "102", "1", 3).unwrap();
+        let reports = book.drain_audit_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].sequence, 3);
+    }
+}
@@ -0,0 +1,265 @@
+extern crate serde;
+
+pub mod binance {
+    use crate::book::book::{DecimalPriceLevel, DecimalSnapshot, OrderBook, RangeUpdate, SyncState};
+    use crate::error::error::BookError;
+    use serde::{Deserialize, Serialize};
+    use std::collections::VecDeque;
+
+    /// `<symbol>@depth` diff event as sent on the Binance spot websocket.
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub struct DepthUpdateEvent {
+        #[serde(rename = "E")]
+        pub event_time: u64,
+        #[serde(rename = "s")]
+        pub symbol: String,
+        #[serde(rename = "U")]
+        pub first_update_id: u64,
+        #[serde(rename = "u")]
+        pub final_update_id: u64,
+        #[serde(rename = "b")]
+        pub bids: Vec<(String, String)>,
+        #[serde(rename = "a")]
+        pub asks: Vec<(String, String)>,
+    }
+
+    /// Response of `GET /api/v3/depth`.
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub struct DepthSnapshot {
+        #[serde(rename = "lastUpdateId")]
+        pub last_update_id: u64,
+        pub bids: Vec<(String, String)>,
+        pub asks: Vec<(String, String)>,
+    }
+
+    fn to_decimal_levels(levels: &[(String, String)]) -> Vec<DecimalPriceLevel> {
+        levels
+            .iter()
+            .map(|(price, size)| DecimalPriceLevel {
+                price: price.clone(),
+                size: size.clone(),
+            })
+            .collect()
+    }
+
+    impl From<&DepthUpdateEvent> for RangeUpdate {
+        fn from(event: &DepthUpdateEvent) -> Self {
+            RangeUpdate {
+                first_sequence: event.first_update_id,
+                last_sequence: event.final_update_id,
+                bids: to_decimal_levels(&event.bids),
+                asks: to_decimal_levels(&event.asks),
+                checksum: None,
+            }
+        }
+    }
+
+    /// Keeps an `OrderBook` in sync with a Binance diff-depth stream:
+    ///
+    /// 1. diff events are buffered until a REST snapshot is applied,
+    /// 2. buffered events with `u <= lastUpdateId` are dropped,
+    /// 3. the first applied event must satisfy `U <= lastUpdateId + 1 <= u`,
+    /// 4. every following event must start at the previous `u + 1`.
+    ///
+    /// Any violation puts the book back into `SyncState::AwaitingSnapshot`
+    /// and events are buffered again until the next snapshot.
+    #[derive(Debug, Clone)]
+    pub struct BinanceDepthSync {
+        pub book: OrderBook,
+        buffer: VecDeque<DepthUpdateEvent>,
+        max_buffered_events: usize,
+    }
+
+    impl BinanceDepthSync {
+        pub fn new(instrument: &str) -> BinanceDepthSync {
+            let mut book = OrderBook::new(instrument, 0);
+            book.request_snapshot();
+            BinanceDepthSync {
+                book,
+                buffer: VecDeque::new(),
+                max_buffered_events: 1000,
+            }
+        }
+
+        pub fn set_max_buffered_events(&mut self, max_buffered_events: usize) {
+            self.max_buffered_events = max_buffered_events;
+        }
+
+        pub fn needs_snapshot(&self) -> bool {
+            self.book.sync_state() == SyncState::AwaitingSnapshot
+        }
+
+        pub fn buffered_event_count(&self) -> usize {
+            self.buffer.len()
+        }
+
+        /// Applies the event when the book is synced, otherwise buffers it.
+        /// Returns true if the book is synced after the event; events with
+        /// malformed levels are rejected without touching the book.
+        pub fn on_depth_update(&mut self, event: DepthUpdateEvent) -> Result<bool, BookError> {
+            if !self.book.is_stale() {
+                match self.book.update_range(RangeUpdate::from(&event)) {
+                    Ok(()) | Err(BookError::StaleSequence { .. }) => return Ok(true),
+                    Err(BookError::SequenceGap { .. }) => {}
+                    Err(error) => return Err(error),
+                }
+            }
+            self.buffer.push_back(event);
+            if self.buffer.len() > self.max_buffered_events {
+                self.buffer.pop_front();
+            }
+            Ok(false)
+        }
+
+        /// Loads the snapshot and replays the buffered events on top of it.
+        /// Fails with `SequenceGap` if the snapshot is older than the buffered
+        /// events, in which case a newer snapshot has to be fetched. If an
+        /// event fails to replay, the events after it stay buffered for that
+        /// snapshot.
+        pub fn on_snapshot(&mut self, snapshot: DepthSnapshot) -> Result<(), BookError> {
+            let last_update_id = snapshot.last_update_id;
+            let decimal_snapshot = DecimalSnapshot {
+                instrument: self.book.instrument.clone(),
+                sequence: last_update_id,
+                bids: to_decimal_levels(&snapshot.bids),
+                asks: to_decimal_levels(&snapshot.asks),
+            };
+            while let Some(event) = self.buffer.front() {
+                if event.final_update_id > last_update_id {
+                    break;
+                }
+                self.buffer.pop_front();
+            }
+            if let Some(first) = self.buffer.front() {
+                if first.first_update_id > last_update_id + 1 {
+                    self.book.request_snapshot();
+                    return Err(BookError::SequenceGap {
+                        expected: last_update_id + 1,
+                        received: first.first_update_id,
+                    });
+                }
+            }
+            if let Err(error) = self.book.apply_decimal_snapshot(decimal_snapshot) {
+                self.book.request_snapshot();
+                return Err(error);
+            }
+            while let Some(event) = self.buffer.pop_front() {
+                match self.book.update_range(RangeUpdate::from(&event)) {
+                    Ok(()) | Err(BookError::StaleSequence { .. }) => {}
+                    Err(error) => {
+                        // the next snapshot may still be bridged by the events after it
+                        self.book.request_snapshot();
+                        return Err(error);
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::binance::{BinanceDepthSync, DepthSnapshot, DepthUpdateEvent};
+    use crate::book::book::SyncState;
+    use crate::error::error::BookError;
+    use bigdecimal::BigDecimal;
+    use prost::Message;
+    use std::str::FromStr;
+    use stock_messages::stock_messages::SnapshotMessage;
+
+    fn depth_snapshot(last_update_id: u64) -> DepthSnapshot {
+        let bytes = std::fs::read("snapshots/Binance:BTC_USDT").unwrap();
+        let snapshot = SnapshotMessage::decode(bytes).unwrap();
+        DepthSnapshot {
+            last_update_id,
+            bids: snapshot.bids.iter().map(|level| (level.price.to_string(), level.total_size.to_string())).collect(),
+            asks: snapshot.asks.iter().map(|level| (level.price.to_string(), level.total_size.to_string())).collect(),
+        }
+    }
+
+    fn depth_update(first_update_id: u64, final_update_id: u64, bids: Vec<(&str, &str)>) -> DepthUpdateEvent {
+        DepthUpdateEvent {
+            event_time: 0,
+            symbol: "BTCUSDT".to_string(),
+            first_update_id,
+            final_update_id,
+            bids: bids.iter().map(|(price, size)| (price.to_string(), size.to_string())).collect(),
+            asks: vec![],
+        }
+    }
+
+    #[test]
+    fn test_buffered_events_replayed_after_snapshot() {
+        let mut sync = BinanceDepthSync::new("Binance:BTC/USDT");
+        assert!(sync.needs_snapshot());
+        assert_eq!(sync.on_depth_update(depth_update(990, 995, vec![("9016.00", "5.00000000")])), Ok(false));
+        assert_eq!(sync.on_depth_update(depth_update(996, 1003, vec![("9015.85", "0.00000000")])), Ok(false));
+        assert_eq!(sync.on_depth_update(depth_update(1004, 1010, vec![("9015.90", "1.25000000")])), Ok(false));
+        assert_eq!(sync.buffered_event_count(), 3);
+
+        assert_eq!(sync.on_snapshot(depth_snapshot(1000)), Ok(()));
+        assert_eq!(sync.book.sync_state(), SyncState::Synced);
+        assert_eq!(sync.book.sequence, 1010);
+        assert_eq!(sync.buffered_event_count(), 0);
+        assert_eq!(sync.book.get_best_bid(), 9015.9);
+        assert!(!sync.book.bids.contains_key(&BigDecimal::from_str("9015.85").unwrap()));
+        assert!(!sync.book.bids.contains_key(&BigDecimal::from_str("9016").unwrap()));
+
+        // already covered
+        assert_eq!(sync.on_depth_update(depth_update(1005, 1010, vec![])), Ok(true));
+        assert_eq!(sync.on_depth_update(depth_update(1011, 1012, vec![("9015.90", "0")])), Ok(true));
+        assert_eq!(sync.book.get_best_bid(), 9015.84);
+    }
+
+    #[test]
+    fn test_snapshot_older_than_buffer() {
+        let mut sync = BinanceDepthSync::new("Binance:BTC/USDT");
+        assert_eq!(sync.on_depth_update(depth_update(1004, 1010, vec![("9015.90", "1.25")])), Ok(false));
+        assert_eq!(
+            sync.on_snapshot(depth_snapshot(1000)),
+            Err(BookError::SequenceGap { expected: 1001, received: 1004 })
+        );
+        assert!(sync.needs_snapshot());
+        assert_eq!(sync.buffered_event_count(), 1);
+
+        assert_eq!(sync.on_snapshot(depth_snapshot(1005)), Ok(()));
+        assert_eq!(sync.book.sequence, 1010);
+    }
+
+    #[test]
+    fn test_gap_requires_new_snapshot() {
+        let mut sync = BinanceDepthSync::new("Binance:BTC/USDT");
+        assert_eq!(sync.on_snapshot(depth_snapshot(1000)), Ok(()));
+        assert_eq!(sync.on_depth_update(depth_update(1001, 1002, vec![])), Ok(true));
+        assert_eq!(sync.on_depth_update(depth_update(1005, 1006, vec![])), Ok(false));
+        assert!(sync.needs_snapshot());
+        assert!(sync.book.is_stale());
+        assert_eq!(sync.on_depth_update(depth_update(1007, 1008, vec![])), Ok(false));
+        assert_eq!(sync.buffered_event_count(), 2);
+
+        assert_eq!(sync.on_snapshot(depth_snapshot(1006)), Ok(()));
+        assert_eq!(sync.book.sequence, 1008);
+        assert!(!sync.book.is_stale());
+    }
+
+    #[test]
+    fn test_replay_failure_keeps_later_events() {
+        let mut sync = BinanceDepthSync::new("Binance:BTC/USDT");
+        assert_eq!(sync.on_depth_update(depth_update(996, 1003, vec![("9015.85", "0")])), Ok(false));
+        assert_eq!(sync.on_depth_update(depth_update(1010, 1012, vec![])), Ok(false));
+        assert_eq!(sync.on_depth_update(depth_update(1013, 1015, vec![("9015.90", "1.25")])), Ok(false));
+
+        assert_eq!(
+            sync.on_snapshot(depth_snapshot(1000)),
+            Err(BookError::SequenceGap { expected: 1004, received: 1010 })
+        );
+        assert!(sync.needs_snapshot());
+        assert_eq!(sync.buffered_event_count(), 1);
+
+        assert_eq!(sync.on_snapshot(depth_snapshot(1013)), Ok(()));
+        assert_eq!(sync.book.sequence, 1015);
+        assert_eq!(sync.book.get_best_bid(), 9015.9);
+        assert_eq!(sync.book.sync_state(), SyncState::Synced);
+    }
+}
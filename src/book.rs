@@ -5,11 +5,14 @@ extern crate stock_messages;
 extern crate colored;
 
 pub mod book {
-    use crate::book_utils::book::group;
-    use crate::book_utils::book::value_to_scale;
+    use crate::audit::audit::AuditReport;
+    use crate::checksum::checksum::ChecksumAlgorithm;
+    use crate::book_utils::book::{group_size_decimal, to_plain_string};
+    use crate::consumer::consumer::{GroupedSnapshotChanges, SnapshotConsumer};
+    use crate::error::error::BookError;
+    use crate::grouping::grouping::{group_levels, GroupingStrategy, GroupingView};
     use crate::itertools::Itertools;
     use bigdecimal::BigDecimal;
-    use bigdecimal::RoundingMode;
     use num_traits::cast::ToPrimitive;
     use num_traits::identities::Zero;
     use num_traits::FromPrimitive;
@@ -20,6 +23,7 @@ pub mod book {
     use std::convert::TryInto;
     use std::ops::Bound::Included;
     use std::ops::{Add, Mul, Sub};
+    use std::str::FromStr;
     use std::time::SystemTime;
     use stock_messages::stock_messages::{
         BookInfo, LevelUpdate, PriceLevel, Side, SnapshotMessage, Type,
@@ -76,6 +80,7 @@ pub mod book {
         pub value: Value,
     }
 
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum OrderType {
         Bid = 1,
         Ask = 2,
@@ -89,6 +94,14 @@ pub mod book {
                 value: BigDecimal::from_f64(price).unwrap_or_default().mul(BigDecimal::from_f64(size).unwrap_or_default()),
             }
         }
+
+        pub fn from_decimal(price: Price, size: Size) -> Level {
+            Level {
+                value: (&price).mul(&size),
+                price,
+                size,
+            }
+        }
     }
 
     impl From<PriceLevel> for Level {
@@ -128,6 +141,253 @@ pub mod book {
         }
     }
 
+    /// Price level carried as the decimal strings sent by the exchange, so no
+    /// precision is lost on the way in or out of the book.
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub struct DecimalPriceLevel {
+        pub price: String,
+        pub size: String,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub struct DecimalLevelUpdate {
+        pub side: i32,
+        pub price: String,
+        pub size: String,
+        pub sequence: i32,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub struct DecimalSnapshot {
+        pub instrument: String,
+        pub sequence: u64,
+        pub bids: Vec<DecimalPriceLevel>,
+        pub asks: Vec<DecimalPriceLevel>,
+    }
+
+    /// Update covering a range of exchange sequence numbers, such as a Binance
+    /// diff-depth event (`U` to `u`). A size of zero removes the level from
+    /// the side it is listed under.
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub struct RangeUpdate {
+        pub first_sequence: u64,
+        pub last_sequence: u64,
+        pub bids: Vec<DecimalPriceLevel>,
+        pub asks: Vec<DecimalPriceLevel>,
+        /// Venue checksum of the book after the update, verified with the
+        /// book's `ChecksumAlgorithm` when both are present.
+        #[serde(default)]
+        pub checksum: Option<i64>,
+    }
+
+    impl From<&Level> for DecimalPriceLevel {
+        fn from(level: &Level) -> Self {
+            DecimalPriceLevel {
+                price: to_plain_string(&level.price),
+                size: to_plain_string(&level.size),
+            }
+        }
+    }
+
+    impl TryFrom<&DecimalPriceLevel> for Level {
+        type Error = BookError;
+        fn try_from(level: &DecimalPriceLevel) -> Result<Self, Self::Error> {
+            let price = BigDecimal::from_str(&level.price).map_err(|_| BookError::InvalidPrice(level.price.clone()))?;
+            let size = BigDecimal::from_str(&level.size).map_err(|_| BookError::InvalidSize(level.size.clone()))?;
+            Ok(Level::from_decimal(price, size))
+        }
+    }
+
+    fn to_order_type(side: i32) -> Result<OrderType, BookError> {
+        match Side::from_i32(side) {
+            Some(Side::Buy) => Ok(OrderType::Bid),
+            Some(_) => Ok(OrderType::Ask),
+            None => Err(BookError::UnknownSide(side)),
+        }
+    }
+
+    /// Splits a stream of varint length-delimited `LevelUpdate`s, as written by
+    /// `Message::encode_length_delimited`, into its messages.
+    pub fn decode_level_updates(bytes: &[u8]) -> Result<Vec<LevelUpdate>, BookError> {
+        let mut updates = Vec::new();
+        let mut position = 0;
+        while position < bytes.len() {
+            let mut length: u64 = 0;
+            let mut shift = 0;
+            loop {
+                let byte = *bytes
+                    .get(position)
+                    .ok_or_else(|| BookError::Decode("truncated length delimiter".to_string()))?;
+                position += 1;
+                if shift > 63 {
+                    return Err(BookError::Decode("invalid length delimiter".to_string()));
+                }
+                length |= u64::from(byte & 0x7f) << shift;
+                shift += 7;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            // compared before converting, so a huge length cannot overflow or truncate
+            if length > (bytes.len() - position) as u64 {
+                return Err(BookError::Decode("truncated message".to_string()));
+            }
+            let end = position + length as usize;
+            updates.push(LevelUpdate::decode(&bytes[position..end])?);
+            position = end;
+        }
+        Ok(updates)
+    }
+
+    fn to_decimal(value: f64) -> Option<BigDecimal> {
+        if value.is_finite() {
+            BigDecimal::from_f64(value)
+        } else {
+            None
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+    pub enum LevelChange {
+        Add = 0,
+        Change = 1,
+        Delete = 2,
+    }
+
+    /// Level change implied by a partial snapshot, or a level update reaching
+    /// the large level size. `size` is zero for deletes.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct LevelEvent {
+        pub side: OrderType,
+        pub change: LevelChange,
+        pub price: Price,
+        pub previous_size: Size,
+        pub size: Size,
+    }
+
+    /// How the size of a level update is applied to the level.
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+    pub enum UpdateMode {
+        /// The size replaces the level; zero removes it.
+        Absolute = 0,
+        /// The signed size is added to the level; a level reaching zero is removed.
+        Delta = 1,
+    }
+
+    /// What a `Delta` book does with an update that would leave a level below zero.
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+    pub enum NegativeLevelPolicy {
+        /// Remove the level as if it had reached zero.
+        Remove = 0,
+        /// Leave the level unchanged and fail with `NegativeLevel`.
+        Reject = 1,
+        /// Fail with `NegativeLevel` and mark the book as awaiting a snapshot.
+        RequestSnapshot = 2,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+    pub enum CrossState {
+        Normal = 0,
+        /// The best bid equals the best ask.
+        Locked = 1,
+        /// The best bid is above the best ask.
+        Crossed = 2,
+    }
+
+    /// What the book does when an update leaves it locked or crossed.
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+    pub enum CrossPolicy {
+        /// Only report the state in `cross_state` and `OrderBookInfo`.
+        FlagOnly = 0,
+        /// Remove the crossing levels of the side that was not updated last,
+        /// they are the ones left behind by a missed delete.
+        DropOlderSide = 1,
+        /// Fail with `CrossedBook` and mark the book as awaiting a snapshot.
+        Resync = 2,
+    }
+
+    /// Number of updates buffered behind a gap before the book gives up waiting
+    /// for the missing sequence and asks for a snapshot.
+    const DEFAULT_GAP_TOLERANCE: usize = 32;
+    const MAX_PENDING_UPDATES: usize = 10000;
+    const MAX_AUDIT_REPORTS: usize = 1000;
+    const MAX_SYNC_EVENTS: usize = 1000;
+    const MAX_LARGE_LEVEL_EVENTS: usize = 1000;
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+    pub enum SyncState {
+        /// Every update up to `sequence` has been applied.
+        Synced = 0,
+        /// An update arrived ahead of `sequence + 1`; later updates are
+        /// buffered in case the missing one arrives out of order.
+        Gapped = 1,
+        /// The gap could not be filled, a snapshot is required.
+        AwaitingSnapshot = 2,
+        /// A snapshot was applied and buffered updates are being replayed.
+        Resyncing = 3,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub struct SyncEvent {
+        pub previous: SyncState,
+        pub state: SyncState,
+        pub sequence: u64,
+    }
+
+    #[derive(Debug, Clone)]
+    pub(crate) enum PendingUpdate {
+        Level(LevelUpdate),
+        Decimal(DecimalLevelUpdate),
+    }
+
+    impl PendingUpdate {
+        fn sequence(&self) -> i32 {
+            match self {
+                PendingUpdate::Level(update) => update.sequence,
+                PendingUpdate::Decimal(update) => update.sequence,
+            }
+        }
+
+        // Rejects malformed updates before they take a sequence number.
+        fn validate(&self, update_mode: UpdateMode) -> Result<(), BookError> {
+            match self {
+                PendingUpdate::Level(update) => {
+                    to_order_type(update.side)?;
+                    if !(update.price.is_finite() && update.price > 0.0) {
+                        return Err(BookError::InvalidPrice(update.price.to_string()));
+                    }
+                    if !update.size.is_finite() || (update_mode == UpdateMode::Absolute && update.size < 0.0) {
+                        return Err(BookError::InvalidSize(update.size.to_string()));
+                    }
+                }
+                PendingUpdate::Decimal(update) => {
+                    to_order_type(update.side)?;
+                    let level = Level::try_from(&DecimalPriceLevel {
+                        price: update.price.clone(),
+                        size: update.size.clone(),
+                    })?;
+                    validate_update(&level.price, &level.size, update_mode)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn validate_level(price: &Price, size: &Size) -> Result<(), BookError> {
+        validate_update(price, size, UpdateMode::Absolute)
+    }
+
+    // Deltas may be negative, absolute sizes may not.
+    fn validate_update(price: &Price, size: &Size, update_mode: UpdateMode) -> Result<(), BookError> {
+        if price <= &BigDecimal::zero() {
+            return Err(BookError::InvalidPrice(to_plain_string(price)));
+        }
+        if update_mode == UpdateMode::Absolute && size < &BigDecimal::zero() {
+            return Err(BookError::InvalidSize(to_plain_string(size)));
+        }
+        Ok(())
+    }
+
     #[derive(Debug, Clone)]
     pub struct OrderBook {
         pub instrument: String,
@@ -142,7 +402,37 @@ pub mod book {
 
         pub grouped_bids: BTreeMap<Price, Size>,
         pub grouped_asks: BTreeMap<Price, Size>,
-        group_size: f64, // orderPool: OrderPool = {};
+        grouping: GroupingStrategy, // orderPool: OrderPool = {};
+        grouping_views: BTreeMap<String, GroupingView>,
+
+        sync_state: SyncState,
+        pub(crate) sync_events: Vec<SyncEvent>,
+        pub(crate) pending_updates: BTreeMap<i32, PendingUpdate>,
+        gap_tolerance: usize,
+
+        audit_mode: bool,
+        pub(crate) audit_reports: Vec<AuditReport>,
+
+        update_mode: UpdateMode,
+        negative_level_policy: NegativeLevelPolicy,
+
+        cross_state: CrossState,
+        cross_policy: CrossPolicy,
+        last_updated_side: Option<OrderType>,
+
+        checksum_algorithm: Option<ChecksumAlgorithm>,
+
+        max_depth: Option<usize>,
+        // levels were purged beyond `max_depth` since the last snapshot
+        depth_purged: bool,
+
+        tick_size: Option<f64>,
+
+        large_level_size: Option<Size>,
+        pub(crate) large_level_events: Vec<LevelEvent>,
+
+        snapshot_consumers: BTreeMap<u32, SnapshotConsumer>,
+        next_consumer_id: u32,
     }
 
     #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -172,6 +462,10 @@ pub mod book {
         pub bids_total: f64,
         pub spread: String,
         pub sequence: u64,
+        pub cross_state: CrossState,
+        /// Levels beyond `max_depth` may have been purged, the totals then only
+        /// cover the window.
+        pub depth_truncated: bool,
     }
 
     #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -185,6 +479,50 @@ pub mod book {
         pub cum_ask_values: Vec<SnapshotLevel>,
     }
 
+    // Bucket prices of the grouped ladder, `count` bids down and `count` asks
+    // up from the buckets around the grouped mid price.
+    fn grouped_ladder(
+        grouping: &GroupingStrategy,
+        grouped_bids: &BTreeMap<Price, Size>,
+        grouped_asks: &BTreeMap<Price, Size>,
+        count: usize,
+    ) -> (Vec<Price>, Vec<Price>) {
+        let mid_price = grouped_mid(grouped_bids, grouped_asks);
+
+        let mut bid_groups_to_include = Vec::with_capacity(count);
+        let mut ask_groups_to_include = Vec::with_capacity(count);
+
+        let mut bid = grouping.bucket(&mid_price, true);
+        let mut ask = grouping.bucket(&mid_price, false);
+        for _ in 0..count {
+            bid_groups_to_include.push(bid.clone());
+            bid = grouping.next_bucket(&bid, false);
+
+            ask_groups_to_include.push(ask.clone());
+            ask = grouping.next_bucket(&ask, true);
+        }
+        (bid_groups_to_include, ask_groups_to_include)
+    }
+
+    // Mid between the best bid and ask buckets the ladder is centred on, an
+    // empty side counting as 0.
+    fn grouped_mid(grouped_bids: &BTreeMap<Price, Size>, grouped_asks: &BTreeMap<Price, Size>) -> Price {
+        let zero = BigDecimal::zero();
+        let two = BigDecimal::from_f32(2.0).unwrap();
+        let best_bid = grouped_bids.keys().rev().next().unwrap_or(&zero);
+        let best_ask = grouped_asks.keys().next().unwrap_or(&zero);
+        (best_bid + best_ask) / two
+    }
+
+    fn grouped_level(grouped: &BTreeMap<Price, Size>, price: &Price) -> SnapshotLevel {
+        SnapshotLevel {
+            price: price.to_f64().unwrap(),
+            total_size: grouped.get(price).map_or(0.0, |size| size.to_f64().unwrap_or(0.0)),
+            total_value: 0.0,
+            relative_size: 0,
+        }
+    }
+
     impl From<SnapshotMessage> for OrderBook {
         fn from(snapshot: SnapshotMessage) -> Self {
             let sequence: u64 = snapshot.source_sequence.try_into().unwrap_or(0u64);
@@ -193,13 +531,13 @@ pub mod book {
                 .bids
                 .iter()
                 .fold(BigDecimal::zero(), |total, current| {
-                    total.add(BigDecimal::from_f64(current.price).unwrap_or_default())
+                    total.add(BigDecimal::from_f64(current.total_size).unwrap_or_default())
                 });
             book.asks_total = snapshot
                 .asks
                 .iter()
                 .fold(BigDecimal::zero(), |total, current| {
-                    total.add(BigDecimal::from_f64(current.price).unwrap_or_default())
+                    total.add(BigDecimal::from_f64(current.total_size).unwrap_or_default())
                 });
             book.bids_value_total =
                 snapshot
@@ -232,17 +570,46 @@ pub mod book {
                 .map(|pricelevel| (BigDecimal::from_f64(pricelevel.price).unwrap_or_default(), Level::from(pricelevel)))
                 .collect();
             book.refresh_groupings();
+            book.cross_state = book.detect_cross_state();
             book
         }
     }
 
     impl TryFrom<Vec<u8>> for OrderBook {
-        type Error = &'static str;
+        type Error = BookError;
         fn try_from(buf: Vec<u8>) -> Result<Self, Self::Error> {
-            let snapshot_decode = SnapshotMessage::decode(buf);
-            match snapshot_decode {
-                Ok(snapshot) => Ok(OrderBook::from(snapshot)),
-                Err(_) => Err("Failed to decode the snapshot"),
+            let snapshot = SnapshotMessage::decode(buf)?;
+            Ok(OrderBook::from(snapshot))
+        }
+    }
+
+    impl TryFrom<DecimalSnapshot> for OrderBook {
+        type Error = BookError;
+        fn try_from(snapshot: DecimalSnapshot) -> Result<Self, Self::Error> {
+            let mut book = OrderBook::new(&snapshot.instrument, snapshot.sequence);
+            for level in snapshot.bids.iter() {
+                let level = Level::try_from(level)?;
+                validate_level(&level.price, &level.size)?;
+                book.insert_level(OrderType::Bid, level.price, level.size, snapshot.sequence);
+            }
+            for level in snapshot.asks.iter() {
+                let level = Level::try_from(level)?;
+                validate_level(&level.price, &level.size)?;
+                book.insert_level(OrderType::Ask, level.price, level.size, snapshot.sequence);
+            }
+            book.last_updated_side = None;
+            book.cross_state = book.detect_cross_state();
+            Ok(book)
+        }
+    }
+
+    impl Into<DecimalSnapshot> for &OrderBook {
+        fn into(self) -> DecimalSnapshot {
+            DecimalSnapshot {
+                instrument: String::from(&self.instrument),
+                sequence: self.sequence,
+                bids: self.bids.values().rev().map(DecimalPriceLevel::from).collect(),
+                asks: self.asks.values().map(DecimalPriceLevel::from).collect(),
             }
         }
     }
@@ -349,7 +716,7 @@ pub mod book {
         #[cfg(not(target_arch = "wasm32"))]
         pub fn print_grouped_debug(&self) {
             use colored::*;
-            println!("Group Size: {}", self.group_size);
+            println!("Grouping: {:?}", self.grouping);
             for (price, size) in self.grouped_asks.iter().take(100).rev() {
                 println!("{} {}", price.to_string().red(), size.to_string().red());
             }
@@ -371,45 +738,378 @@ pub mod book {
                 asks_value_total: BigDecimal::zero(),
                 grouped_bids: BTreeMap::new(),
                 grouped_asks: BTreeMap::new(),
-                group_size: 1.0,
+                grouping: GroupingStrategy::default(),
+                grouping_views: BTreeMap::new(),
+                sync_state: SyncState::Synced,
+                sync_events: Vec::new(),
+                pending_updates: BTreeMap::new(),
+                gap_tolerance: DEFAULT_GAP_TOLERANCE,
+                audit_mode: false,
+                audit_reports: Vec::new(),
+                update_mode: UpdateMode::Absolute,
+                negative_level_policy: NegativeLevelPolicy::Reject,
+                cross_state: CrossState::Normal,
+                cross_policy: CrossPolicy::FlagOnly,
+                last_updated_side: None,
+                checksum_algorithm: None,
+                max_depth: None,
+                depth_purged: false,
+                tick_size: None,
+                large_level_size: None,
+                large_level_events: Vec::new(),
+                snapshot_consumers: BTreeMap::new(),
+                next_consumer_id: 0,
             }
         }
 
         pub fn set_group_size(&mut self, group_size: f64) {
-            self.group_size = group_size;
+            self.grouping = GroupingStrategy::Absolute(group_size);
             self.refresh_groupings();
         }
 
-        pub fn refresh_groupings(&mut self) {
-            let group_size = self.group_size;
-            let mut grouped_bids = BTreeMap::new();
-            let mut grouped_asks = BTreeMap::new();
+        /// Width of the default grouping's buckets, 0 for logarithmic grouping.
+        pub fn get_group_size(&self) -> f64 {
+            self.grouping.group_size().unwrap_or(0.0)
+        }
+
+        pub fn grouping_strategy(&self) -> GroupingStrategy {
+            self.grouping
+        }
+
+        pub fn set_grouping_strategy(&mut self, strategy: GroupingStrategy) -> Result<(), BookError> {
+            strategy.validate()?;
+            self.grouping = strategy;
+            self.refresh_groupings();
+            Ok(())
+        }
+
+        /// Adds or replaces a named grouping kept alongside the default one, so
+        /// several zoom levels can be served without regrouping the book.
+        pub fn add_grouping_view(&mut self, name: &str, strategy: GroupingStrategy) -> Result<(), BookError> {
+            strategy.validate()?;
+            let view = GroupingView::new(strategy, &self.bids, &self.asks);
+            self.grouping_views.insert(name.to_string(), view);
+            self.invalidate_consumers();
+            Ok(())
+        }
+
+        /// Registers a reader of `get_grouped_snapshot_changes` over the named
+        /// grouping view, or the default grouping for None.
+        pub fn register_snapshot_consumer(&mut self, view: Option<&str>) -> Result<u32, BookError> {
+            if let Some(name) = view {
+                if !self.grouping_views.contains_key(name) {
+                    return Err(BookError::UnknownView(name.to_string()));
+                }
+            }
+            let consumer = self.next_consumer_id;
+            self.next_consumer_id += 1;
+            self.snapshot_consumers.insert(consumer, SnapshotConsumer::new(view.map(str::to_string)));
+            Ok(consumer)
+        }
+
+        pub fn unregister_snapshot_consumer(&mut self, consumer: u32) -> bool {
+            self.snapshot_consumers.remove(&consumer).is_some()
+        }
+
+        pub fn snapshot_consumers(&self) -> &BTreeMap<u32, SnapshotConsumer> {
+            &self.snapshot_consumers
+        }
+
+        /// The grouped levels of the ladder that changed since the consumer's
+        /// previous call, or the whole ladder if it moved.
+        pub fn get_grouped_snapshot_changes(&mut self, consumer: u32, count: usize) -> Result<GroupedSnapshotChanges, BookError> {
+            let state = self
+                .snapshot_consumers
+                .get(&consumer)
+                .ok_or(BookError::UnknownConsumer(consumer))?;
+            let (grouping, grouped_bids, grouped_asks) = match &state.view {
+                Some(name) => {
+                    let view = self
+                        .grouping_views
+                        .get(name)
+                        .ok_or_else(|| BookError::UnknownView(name.clone()))?;
+                    (&view.strategy, &view.grouped_bids, &view.grouped_asks)
+                }
+                None => (&self.grouping, &self.grouped_bids, &self.grouped_asks),
+            };
+            let (bid_prices, ask_prices) = grouped_ladder(grouping, grouped_bids, grouped_asks, count);
+            let anchor = (
+                bid_prices.first().cloned().unwrap_or_default(),
+                ask_prices.first().cloned().unwrap_or_default(),
+            );
+            let full = state.needs_full || state.count != count || state.anchor.as_ref() != Some(&anchor);
+            let bids = bid_prices
+                .iter()
+                .filter(|price| full || state.dirty_bids.contains(price))
+                .map(|price| grouped_level(grouped_bids, price))
+                .collect();
+            let asks = ask_prices
+                .iter()
+                .filter(|price| full || state.dirty_asks.contains(price))
+                .map(|price| grouped_level(grouped_asks, price))
+                .collect();
+
+            if let Some(state) = self.snapshot_consumers.get_mut(&consumer) {
+                state.anchor = Some(anchor);
+                state.count = count;
+                state.needs_full = false;
+                state.dirty_bids.clear();
+                state.dirty_asks.clear();
+            }
+            Ok(GroupedSnapshotChanges { full, bids, asks })
+        }
+
+        fn mark_consumers_dirty(&mut self, order_type: OrderType, price: &Price) {
+            let group_lower = order_type == OrderType::Bid;
+            for consumer in self.snapshot_consumers.values_mut() {
+                let strategy = match &consumer.view {
+                    Some(name) => match self.grouping_views.get(name) {
+                        Some(view) => view.strategy,
+                        None => {
+                            consumer.invalidate();
+                            continue;
+                        }
+                    },
+                    None => self.grouping,
+                };
+                consumer.mark_dirty(order_type, strategy.bucket(price, group_lower));
+            }
+        }
+
+        fn invalidate_consumers(&mut self) {
+            for consumer in self.snapshot_consumers.values_mut() {
+                consumer.invalidate();
+            }
+        }
+
+        pub fn remove_grouping_view(&mut self, name: &str) -> bool {
+            self.grouping_views.remove(name).is_some()
+        }
+
+        pub fn grouping_view(&self, name: &str) -> Option<&GroupingView> {
+            self.grouping_views.get(name)
+        }
+
+        pub fn grouping_views(&self) -> &BTreeMap<String, GroupingView> {
+            &self.grouping_views
+        }
+
+        pub fn update_mode(&self) -> UpdateMode {
+            self.update_mode
+        }
+
+        /// Selects how level updates and range updates are applied. Snapshots
+        /// and `add_level`/`remove_level` always set absolute sizes.
+        pub fn set_update_mode(&mut self, update_mode: UpdateMode) {
+            self.update_mode = update_mode;
+        }
+
+        pub fn set_negative_level_policy(&mut self, negative_level_policy: NegativeLevelPolicy) {
+            self.negative_level_policy = negative_level_policy;
+        }
+
+        /// State found by the check that runs after every update and snapshot.
+        pub fn cross_state(&self) -> CrossState {
+            self.cross_state
+        }
+
+        pub fn set_cross_policy(&mut self, cross_policy: CrossPolicy) {
+            self.cross_policy = cross_policy;
+        }
+
+        /// Sets the tick size of the instrument, None to infer it from the levels.
+        pub fn set_tick_size(&mut self, tick_size: Option<f64>) {
+            self.tick_size = tick_size;
+        }
+
+        /// The configured tick size, or else the smallest gap between two
+        /// adjacent levels of the book.
+        pub fn tick_size(&self) -> Option<f64> {
+            if self.tick_size.is_some() {
+                return self.tick_size;
+            }
+            let smallest_gap = |levels: &BTreeMap<Price, Level>| {
+                // keys may carry the binary expansion of f64 prices, compare them as printed
+                let prices = levels
+                    .keys()
+                    .map(|price| group_size_decimal(price.to_f64().unwrap_or_default()))
+                    .collect::<Vec<BigDecimal>>();
+                prices
+                    .windows(2)
+                    .map(|pair| &pair[1] - &pair[0])
+                    .filter(|gap| gap > &BigDecimal::zero())
+                    .min()
+            };
+            let gaps = [smallest_gap(&self.bids), smallest_gap(&self.asks)];
+            gaps.iter().flatten().min().and_then(|gap| gap.to_f64())
+        }
+
+        /// Keeps at most `max_depth` levels per side, for feeds that only
+        /// maintain the top of the book and never delete levels leaving it.
+        /// Levels pushed out of the window are purged as updates arrive.
+        pub fn set_max_depth(&mut self, max_depth: Option<usize>) {
+            self.max_depth = max_depth;
+            self.purge_beyond_depth(OrderType::Bid);
+            self.purge_beyond_depth(OrderType::Ask);
+        }
+
+        pub fn max_depth(&self) -> Option<usize> {
+            self.max_depth
+        }
+
+        /// Level updates taking a level from below `size` to at least `size`
+        /// are recorded as large level events, None stops recording them.
+        pub fn set_large_level_size(&mut self, size: Option<f64>) {
+            self.large_level_size = size.and_then(to_decimal);
+        }
+
+        pub fn drain_large_level_events(&mut self) -> Vec<LevelEvent> {
+            std::mem::take(&mut self.large_level_events)
+        }
+
+        /// Whether levels beyond `max_depth` may be missing: a side is at the
+        /// limit, or levels were purged since the last snapshot.
+        pub fn is_depth_truncated(&self) -> bool {
+            match self.max_depth {
+                Some(max_depth) => self.depth_purged || self.bids.len() >= max_depth || self.asks.len() >= max_depth,
+                None => false,
+            }
+        }
+
+        fn purge_beyond_depth(&mut self, order_type: OrderType) {
+            let max_depth = match self.max_depth {
+                Some(max_depth) => max_depth,
+                None => return,
+            };
+            let purged = match order_type {
+                OrderType::Bid => self.bids.keys().rev().skip(max_depth).cloned().collect::<Vec<Price>>(),
+                OrderType::Ask => self.asks.keys().skip(max_depth).cloned().collect::<Vec<Price>>(),
+            };
+            let sequence = self.sequence;
+            for price in purged {
+                self.depth_purged = true;
+                self.delete_level(order_type, price, sequence);
+            }
+        }
+
+        pub fn set_checksum_algorithm(&mut self, checksum_algorithm: Option<ChecksumAlgorithm>) {
+            self.checksum_algorithm = checksum_algorithm;
+        }
+
+        pub fn checksum(&self) -> Option<u32> {
+            self.checksum_algorithm.map(|algorithm| algorithm.compute(self))
+        }
+
+        /// Compares the venue checksum with the book. `expected` may be given
+        /// signed (OKX, Bitfinex) or unsigned (Kraken). On a mismatch the book
+        /// is marked as awaiting a snapshot. Passes if no algorithm is set.
+        pub fn verify_checksum(&mut self, expected: i64) -> Result<(), BookError> {
+            let computed = match self.checksum() {
+                Some(computed) => computed,
+                None => return Ok(()),
+            };
+            let expected = expected as u32;
+            if computed != expected {
+                let result = format!(
+                    "CHECKSUM MISMATCH {} at {} expected {} computed {}",
+                    self.instrument, self.sequence, expected, computed
+                );
+                #[cfg(all(feature = "console_error_panic_hook", target_arch = "wasm32"))]
+                web_sys::console::error_1(&result.clone().into());
+                println!("{}", result);
+                self.request_snapshot();
+                return Err(BookError::ChecksumMismatch { expected, computed });
+            }
+            Ok(())
+        }
+
+        fn detect_cross_state(&self) -> CrossState {
+            match (self.bids.keys().next_back(), self.asks.keys().next()) {
+                (Some(best_bid), Some(best_ask)) if best_bid > best_ask => CrossState::Crossed,
+                (Some(best_bid), Some(best_ask)) if best_bid == best_ask => CrossState::Locked,
+                _ => CrossState::Normal,
+            }
+        }
+
+        // Removes the levels of `order_type` that are at or through the best
+        // price of the other side.
+        fn drop_crossing_levels(&mut self, order_type: OrderType) {
+            let crossing = match order_type {
+                OrderType::Bid => match self.asks.keys().next() {
+                    Some(best_ask) => self.bids.range(best_ask.clone()..).map(|(price, _)| price.clone()).collect(),
+                    None => Vec::new(),
+                },
+                OrderType::Ask => match self.bids.keys().next_back() {
+                    Some(best_bid) => self.asks.range(..=best_bid.clone()).map(|(price, _)| price.clone()).collect(),
+                    None => Vec::new(),
+                },
+            };
+            let sequence = self.sequence;
+            let last_updated_side = self.last_updated_side;
+            for price in crossing {
+                self.delete_level(order_type, price, sequence);
+            }
+            self.last_updated_side = last_updated_side;
+        }
 
-            for (_, pricelevel) in &self.asks {
-                let price = pricelevel.price.clone();
-                let size = pricelevel.size.clone();
-                let group_price = group(price, group_size, false);
-                let existing_size = grouped_asks
-                    .entry(group_price)
-                    .or_insert(BigDecimal::zero());
-                *existing_size += size;
+        fn update_cross_state(&mut self) -> Result<(), BookError> {
+            let mut cross_state = self.detect_cross_state();
+            if cross_state != CrossState::Normal {
+                match (self.cross_policy, self.last_updated_side) {
+                    (CrossPolicy::DropOlderSide, Some(OrderType::Bid)) => self.drop_crossing_levels(OrderType::Ask),
+                    (CrossPolicy::DropOlderSide, Some(OrderType::Ask)) => self.drop_crossing_levels(OrderType::Bid),
+                    (CrossPolicy::Resync, _) => {
+                        self.cross_state = cross_state;
+                        self.request_snapshot();
+                        return Err(BookError::CrossedBook {
+                            best_bid: self.get_best_bid(),
+                            best_ask: self.get_best_ask(),
+                        });
+                    }
+                    // a snapshot has no older side
+                    _ => {}
+                }
+                cross_state = self.detect_cross_state();
             }
+            self.cross_state = cross_state;
+            Ok(())
+        }
+
+        /// Debug mode: audits the book after every level change and keeps the
+        /// reports that found divergences until they are drained.
+        pub fn set_audit_mode(&mut self, audit_mode: bool) {
+            self.audit_mode = audit_mode;
+        }
+
+        pub fn drain_audit_reports(&mut self) -> Vec<AuditReport> {
+            std::mem::take(&mut self.audit_reports)
+        }
 
-            for (_, pricelevel) in &self.bids {
-                let price = pricelevel.price.clone();
-                let size = pricelevel.size.clone();
-                let group_price = group(price, group_size, true);
-                let existing_size = grouped_bids
-                    .entry(group_price)
-                    .or_insert(BigDecimal::zero());
-                *existing_size += size;
+        fn audit_after_update(&mut self) {
+            if !self.audit_mode {
+                return;
             }
+            let report = self.audit();
+            if report.is_clean() {
+                return;
+            }
+            let result = report.to_string();
+            #[cfg(all(feature = "console_error_panic_hook", target_arch = "wasm32"))]
+            web_sys::console::error_1(&result.clone().into());
+            println!("{}", result);
+            self.audit_reports.push(report);
+            if self.audit_reports.len() > MAX_AUDIT_REPORTS {
+                self.audit_reports.remove(0);
+            }
+        }
 
-            self.grouped_bids = grouped_bids;
-            self.grouped_asks = grouped_asks;
+        pub fn refresh_groupings(&mut self) {
+            self.grouped_bids = group_levels(&self.bids, &self.grouping, true);
+            self.grouped_asks = group_levels(&self.asks, &self.grouping, false);
+            self.invalidate_consumers();
         }
 
-        pub fn verify_sequence(&self, sequence: i32) -> (bool, bool) {
+        pub fn verify_sequence(&self, sequence: i32) -> Result<(), BookError> {
             let next_sequence = (self.sequence + 1) as i32;
             let received_sequence = sequence;
             if received_sequence < next_sequence {
@@ -417,64 +1117,534 @@ pub mod book {
                     "old sequenece for {} ignoring current received sequence: {} book sequence: {}",
                     self.instrument, received_sequence, next_sequence
                 );
-                #[cfg(feature = "console_error_panic_hook")]
+                #[cfg(all(feature = "console_error_panic_hook", target_arch = "wasm32"))]
                 web_sys::console::error_1(&result.clone().into());
                 println!("{}", result);
-                return (true, true);
+                return Err(BookError::StaleSequence {
+                    expected: next_sequence as u64,
+                    received: received_sequence as u64,
+                });
             } else if received_sequence > next_sequence {
                 let result = format!(
                     "SEQUENCE MISMATCH {} received {}, next {}",
                     self.instrument, received_sequence, next_sequence
                 );
-                #[cfg(feature = "console_error_panic_hook")]
+                #[cfg(all(feature = "console_error_panic_hook", target_arch = "wasm32"))]
                 web_sys::console::error_1(&result.clone().into());
                 println!("{}", result);
-                return (true, false);
+                return Err(BookError::SequenceGap {
+                    expected: next_sequence as u64,
+                    received: received_sequence as u64,
+                });
             }
-            return (false, false);
+            return Ok(());
         }
 
-        pub fn update_level_message(&mut self, level_message: LevelUpdate) -> bool {
-            let (stop, valid) = self.verify_sequence(level_message.sequence);
-            if stop {
-                return valid;
+        pub fn sync_state(&self) -> SyncState {
+            self.sync_state
+        }
+
+        /// True while the book is missing updates and should not be trusted.
+        pub fn is_stale(&self) -> bool {
+            self.sync_state != SyncState::Synced
+        }
+
+        pub fn drain_sync_events(&mut self) -> Vec<SyncEvent> {
+            std::mem::take(&mut self.sync_events)
+        }
+
+        pub fn pending_update_count(&self) -> usize {
+            self.pending_updates.len()
+        }
+
+        pub fn set_gap_tolerance(&mut self, gap_tolerance: usize) {
+            self.gap_tolerance = gap_tolerance;
+        }
+
+        fn set_sync_state(&mut self, state: SyncState) {
+            if self.sync_state == state {
+                return;
             }
-            let side = Side::from_i32(level_message.side).unwrap();
-            let order_type = if side == Side::Buy {
-                OrderType::Bid
-            } else {
-                OrderType::Ask
-            };
-            if level_message.size == 0.0 {
-                self.remove_level(
-                    OrderType::Bid,
-                    level_message.price,
-                    level_message.sequence as u64,
-                );
-                self.remove_level(
-                    OrderType::Ask,
-                    level_message.price,
-                    level_message.sequence as u64,
+            self.sync_events.push(SyncEvent {
+                previous: self.sync_state,
+                state,
+                sequence: self.sequence,
+            });
+            if self.sync_events.len() > MAX_SYNC_EVENTS {
+                self.sync_events.remove(0);
+            }
+            self.sync_state = state;
+        }
+
+        /// Marks the book as unusable until the next snapshot arrives.
+        pub fn request_snapshot(&mut self) {
+            self.set_sync_state(SyncState::AwaitingSnapshot);
+        }
+
+        /// Replaces the levels with the snapshot while keeping the book settings,
+        /// then replays any buffered updates newer than the snapshot. Fails with
+        /// `SequenceGap` if the snapshot does not reach the oldest buffered update.
+        pub fn apply_snapshot(&mut self, snapshot: SnapshotMessage) -> Result<(), BookError> {
+            self.replace_levels(OrderBook::from(snapshot));
+            self.resync()
+        }
+
+        pub fn apply_decimal_snapshot(&mut self, snapshot: DecimalSnapshot) -> Result<(), BookError> {
+            self.replace_levels(OrderBook::try_from(snapshot)?);
+            self.resync()
+        }
+
+        fn replace_levels(&mut self, snapshot_book: OrderBook) {
+            self.instrument = snapshot_book.instrument;
+            self.sequence = snapshot_book.sequence;
+            self.bids = snapshot_book.bids;
+            self.asks = snapshot_book.asks;
+            self.bids_total = snapshot_book.bids_total;
+            self.bids_value_total = snapshot_book.bids_value_total;
+            self.asks_total = snapshot_book.asks_total;
+            self.asks_value_total = snapshot_book.asks_value_total;
+            self.last_updated_side = None;
+            self.depth_purged = false;
+            self.refresh_groupings();
+            for view in self.grouping_views.values_mut() {
+                view.rebuild(&self.bids, &self.asks);
+            }
+            self.purge_beyond_depth(OrderType::Bid);
+            self.purge_beyond_depth(OrderType::Ask);
+            self.audit_after_update();
+        }
+
+        fn resync(&mut self) -> Result<(), BookError> {
+            self.set_sync_state(SyncState::Resyncing);
+            self.drain_pending_updates()?;
+            match self.pending_updates.keys().next() {
+                None => {
+                    self.set_sync_state(SyncState::Synced);
+                    self.update_cross_state()
+                }
+                Some(&received) => {
+                    self.set_sync_state(SyncState::AwaitingSnapshot);
+                    Err(BookError::SequenceGap {
+                        expected: self.sequence + 1,
+                        received: received as u64,
+                    })
+                }
+            }
+        }
+
+        /// Applies an update covering the sequences `first_sequence..=last_sequence`.
+        /// It is accepted when it straddles `sequence + 1`; an update starting
+        /// further ahead means updates were missed and a snapshot is required.
+        /// Levels that would go negative are handled by the negative level
+        /// policy as if sent on their own; the rest of the range still applies
+        /// and the first `NegativeLevel` error is returned.
+        pub fn update_range(&mut self, update: RangeUpdate) -> Result<(), BookError> {
+            let expected = self.sequence + 1;
+            if self.sync_state != SyncState::Synced {
+                return Err(BookError::SequenceGap {
+                    expected,
+                    received: update.first_sequence,
+                });
+            }
+            if update.last_sequence < expected {
+                return Err(BookError::StaleSequence {
+                    expected,
+                    received: update.last_sequence,
+                });
+            }
+            if update.first_sequence > expected {
+                let result = format!(
+                    "SEQUENCE MISMATCH {} received {}-{}, next {}",
+                    self.instrument, update.first_sequence, update.last_sequence, self.sequence + 1
                 );
-                // self.refresh_groupings();
-                return true;
+                #[cfg(all(feature = "console_error_panic_hook", target_arch = "wasm32"))]
+                web_sys::console::error_1(&result.clone().into());
+                println!("{}", result);
+                self.request_snapshot();
+                return Err(BookError::SequenceGap {
+                    expected,
+                    received: update.first_sequence,
+                });
+            }
+            let update_mode = self.update_mode;
+            let parse_levels = |levels: &Vec<DecimalPriceLevel>| -> Result<Vec<Level>, BookError> {
+                let levels = levels.iter().map(Level::try_from).collect::<Result<Vec<Level>, BookError>>()?;
+                for level in levels.iter() {
+                    validate_update(&level.price, &level.size, update_mode)?;
+                }
+                Ok(levels)
+            };
+            let bids = parse_levels(&update.bids)?;
+            let asks = parse_levels(&update.asks)?;
+            let sequence = update.last_sequence;
+            // like separate level updates, a level that would go negative is
+            // skipped and the rest of the range still applies
+            let mut rejected = None;
+            for (order_type, levels) in [(OrderType::Bid, bids), (OrderType::Ask, asks)] {
+                for level in levels {
+                    if let Err(error) = self.apply_side_update(order_type, level.price, level.size, sequence) {
+                        rejected.get_or_insert(error);
+                    }
+                }
+            }
+            self.sequence = sequence;
+            self.update_cross_state()?;
+            if let Some(error) = rejected {
+                return Err(error);
+            }
+            match update.checksum {
+                Some(checksum) => self.verify_checksum(checksum),
+                None => Ok(()),
+            }
+        }
+
+        /// Replaces the top `depth` levels of each side with the snapshot in
+        /// place, keeping the group size and the other book settings. Levels
+        /// inside the window covered by the snapshot that it no longer lists are
+        /// deleted; deeper levels are left alone unless the snapshot lists
+        /// fewer than `depth` levels for the side. Returns the implied events.
+        /// A `depth` of 0 covers no window and fails with `InvalidArgument`.
+        pub fn replace_top_levels(&mut self, snapshot: DecimalSnapshot, depth: usize) -> Result<Vec<LevelEvent>, BookError> {
+            let parse_levels = |levels: &Vec<DecimalPriceLevel>| -> Result<Vec<Level>, BookError> {
+                let levels = levels.iter().map(Level::try_from).collect::<Result<Vec<Level>, BookError>>()?;
+                for level in levels.iter() {
+                    validate_level(&level.price, &level.size)?;
+                }
+                Ok(levels)
+            };
+            let bids = parse_levels(&snapshot.bids)?;
+            let asks = parse_levels(&snapshot.asks)?;
+            self.replace_window(bids, asks, snapshot.sequence, depth)
+        }
+
+        pub fn replace_top_levels_message(&mut self, snapshot: SnapshotMessage, depth: usize) -> Result<Vec<LevelEvent>, BookError> {
+            let sequence: u64 = snapshot.source_sequence.try_into().unwrap_or(0u64);
+            let parse_levels = |levels: Vec<PriceLevel>| -> Result<Vec<Level>, BookError> {
+                let mut parsed = Vec::with_capacity(levels.len());
+                for level in levels {
+                    let price = to_decimal(level.price).ok_or_else(|| BookError::InvalidPrice(level.price.to_string()))?;
+                    let size = to_decimal(level.total_size).ok_or_else(|| BookError::InvalidSize(level.total_size.to_string()))?;
+                    validate_level(&price, &size)?;
+                    parsed.push(Level::from_decimal(price, size));
+                }
+                Ok(parsed)
+            };
+            let bids = parse_levels(snapshot.bids)?;
+            let asks = parse_levels(snapshot.asks)?;
+            self.replace_window(bids, asks, sequence, depth)
+        }
+
+        fn replace_window(&mut self, bids: Vec<Level>, asks: Vec<Level>, sequence: u64, depth: usize) -> Result<Vec<LevelEvent>, BookError> {
+            if depth == 0 {
+                return Err(BookError::InvalidArgument("replace depth must be at least 1".to_string()));
+            }
+            if sequence != 0 && sequence < self.sequence {
+                return Err(BookError::StaleSequence {
+                    expected: self.sequence,
+                    received: sequence,
+                });
+            }
+            let sequence = if sequence == 0 { self.sequence } else { sequence };
+            let mut events = Vec::new();
+            for (order_type, levels) in [(OrderType::Bid, bids), (OrderType::Ask, asks)] {
+                let incoming = levels
+                    .into_iter()
+                    .filter(|level| !level.size.is_zero())
+                    .map(|level| (level.price, level.size))
+                    .collect::<BTreeMap<Price, Size>>();
+                let current = match order_type {
+                    OrderType::Bid => &self.bids,
+                    OrderType::Ask => &self.asks,
+                };
+                // prices at or better than the worst level of the snapshot
+                let window: Vec<(Price, Size)> = match (order_type, incoming.len() < depth) {
+                    (_, true) => current.iter().map(|(price, level)| (price.clone(), level.size.clone())).collect(),
+                    (OrderType::Bid, false) => current
+                        .range(incoming.keys().next().cloned().unwrap_or_default()..)
+                        .map(|(price, level)| (price.clone(), level.size.clone()))
+                        .collect(),
+                    (OrderType::Ask, false) => current
+                        .range(..=incoming.keys().next_back().cloned().unwrap_or_default())
+                        .map(|(price, level)| (price.clone(), level.size.clone()))
+                        .collect(),
+                };
+                for (price, previous_size) in window {
+                    if !incoming.contains_key(&price) {
+                        events.push(LevelEvent {
+                            side: order_type,
+                            change: LevelChange::Delete,
+                            price,
+                            previous_size,
+                            size: BigDecimal::zero(),
+                        });
+                    }
+                }
+                for (price, size) in incoming {
+                    let previous_size = current.get(&price).map(|level| level.size.clone());
+                    let change = match previous_size {
+                        None => LevelChange::Add,
+                        Some(ref previous_size) if previous_size != &size => LevelChange::Change,
+                        Some(_) => continue,
+                    };
+                    events.push(LevelEvent {
+                        side: order_type,
+                        change,
+                        price,
+                        previous_size: previous_size.unwrap_or_else(BigDecimal::zero),
+                        size,
+                    });
+                }
+            }
+            for event in events.iter() {
+                if event.change == LevelChange::Delete {
+                    self.delete_level(event.side, event.price.clone(), sequence);
+                } else {
+                    self.insert_level(event.side, event.price.clone(), event.size.clone(), sequence);
+                }
+            }
+            self.sequence = sequence;
+            self.update_cross_state()?;
+            Ok(events)
+        }
+
+        // Size the level would have after `size` is applied in the current mode.
+        fn delta_result(&self, order_type: OrderType, price: &Price, size: &Size) -> Result<Size, BookError> {
+            if self.update_mode == UpdateMode::Absolute {
+                return Ok(size.clone());
+            }
+            let levels = match order_type {
+                OrderType::Bid => &self.bids,
+                OrderType::Ask => &self.asks,
+            };
+            let current = levels.get(price).map(|level| level.size.clone()).unwrap_or_else(BigDecimal::zero);
+            let updated = current.add(size);
+            if updated < BigDecimal::zero() && self.negative_level_policy != NegativeLevelPolicy::Remove {
+                return Err(BookError::NegativeLevel {
+                    price: to_plain_string(price),
+                    size: to_plain_string(&updated),
+                });
+            }
+            Ok(updated)
+        }
+
+        // Applies a level update to the side it names only, honouring the update mode.
+        fn apply_side_update(&mut self, order_type: OrderType, price: Price, size: Size, sequence: u64) -> Result<(), BookError> {
+            let updated = match self.delta_result(order_type, &price, &size) {
+                Ok(updated) => updated,
+                Err(error) => {
+                    self.sequence = sequence;
+                    if self.negative_level_policy == NegativeLevelPolicy::RequestSnapshot {
+                        self.request_snapshot();
+                    }
+                    return Err(error);
+                }
+            };
+            if updated <= BigDecimal::zero() {
+                self.delete_level(order_type, price, sequence);
             } else {
-                self.add_level(
-                    order_type,
-                    level_message.price,
-                    level_message.size,
-                    level_message.sequence as u64,
-                );
-                // self.refresh_groupings();
-                return true;
+                self.insert_level(order_type, price, updated, sequence);
+            }
+            Ok(())
+        }
+
+        // Applies buffered updates for as long as they continue the sequence.
+        // Stops with the error of an update that required a new snapshot.
+        fn drain_pending_updates(&mut self) -> Result<(), BookError> {
+            loop {
+                let next_sequence = (self.sequence + 1) as i32;
+                self.pending_updates = self.pending_updates.split_off(&next_sequence);
+                match self.pending_updates.remove(&next_sequence) {
+                    Some(update) => {
+                        // buffered updates were validated on arrival, a rejected
+                        // negative delta still advances the sequence
+                        if let Err(error) = self.apply_pending_update(update) {
+                            if self.sync_state == SyncState::AwaitingSnapshot {
+                                return Err(error);
+                            }
+                        }
+                    }
+                    None => return Ok(()),
+                }
+            }
+        }
+
+        fn buffer_update(&mut self, update: PendingUpdate) {
+            self.pending_updates.insert(update.sequence(), update);
+            if self.pending_updates.len() > MAX_PENDING_UPDATES {
+                let oldest = *self.pending_updates.keys().next().unwrap();
+                self.pending_updates.remove(&oldest);
+            }
+        }
+
+        fn process_update(&mut self, update: PendingUpdate) -> Result<(), BookError> {
+            update.validate(self.update_mode)?;
+            let sequence = update.sequence();
+            if self.sync_state == SyncState::Synced {
+                return match self.verify_sequence(sequence) {
+                    Ok(()) => self.apply_pending_update(update),
+                    Err(BookError::SequenceGap { expected, received }) => {
+                        self.buffer_update(update);
+                        self.set_sync_state(SyncState::Gapped);
+                        Err(BookError::SequenceGap { expected, received })
+                    }
+                    Err(error) => Err(error),
+                };
+            }
+            if sequence <= self.sequence as i32 {
+                return Err(BookError::StaleSequence {
+                    expected: self.sequence + 1,
+                    received: sequence as u64,
+                });
+            }
+            self.buffer_update(update);
+            if self.sync_state == SyncState::Gapped {
+                self.drain_pending_updates()?;
+                if self.pending_updates.is_empty() {
+                    self.set_sync_state(SyncState::Synced);
+                    return Ok(());
+                }
+                if self.pending_updates.len() > self.gap_tolerance {
+                    self.set_sync_state(SyncState::AwaitingSnapshot);
+                }
             }
+            Err(BookError::SequenceGap {
+                expected: self.sequence + 1,
+                received: sequence as u64,
+            })
+        }
+
+        fn apply_pending_update(&mut self, update: PendingUpdate) -> Result<(), BookError> {
+            match update {
+                PendingUpdate::Level(level_message) => self.apply_level_message(level_message)?,
+                PendingUpdate::Decimal(level_message) => self.apply_level_decimal(level_message)?,
+            }
+            self.update_cross_state()
+        }
+
+        /// Applies the update if it is the next in sequence. Updates ahead of the
+        /// book are buffered and reported as `SequenceGap` until the gap closes.
+        pub fn update_level_message(&mut self, level_message: LevelUpdate) -> Result<(), BookError> {
+            self.process_update(PendingUpdate::Level(level_message))
+        }
+
+        fn apply_level_message(&mut self, level_message: LevelUpdate) -> Result<(), BookError> {
+            let order_type = to_order_type(level_message.side)?;
+            let price = to_decimal(level_message.price).ok_or_else(|| BookError::InvalidPrice(level_message.price.to_string()))?;
+            let size = to_decimal(level_message.size).ok_or_else(|| BookError::InvalidSize(level_message.size.to_string()))?;
+            // self.refresh_groupings();
+            self.apply_side_update(order_type, price, size, level_message.sequence as u64)
         }
 
-        pub fn update_level(&mut self, bytes: Vec<u8>) -> bool {
-            let level_message: LevelUpdate = LevelUpdate::decode(bytes).unwrap();
+        pub fn update_level(&mut self, bytes: Vec<u8>) -> Result<(), BookError> {
+            let level_message: LevelUpdate = LevelUpdate::decode(bytes)?;
             return self.update_level_message(level_message);
         }
 
+        /// Applies a length-delimited stream of `LevelUpdate`s with `apply_batch`.
+        pub fn update_level_batch(&mut self, bytes: Vec<u8>) -> Result<(), BookError> {
+            let updates = decode_level_updates(&bytes)?;
+            self.apply_batch(updates)
+        }
+
+        /// Applies consecutive updates as one. The whole batch is validated and
+        /// each price is resolved to its final size before anything is touched,
+        /// so a malformed update or a sequence gap leaves the levels unchanged. A
+        /// gap is not buffered like a single update, the book asks for a snapshot.
+        /// Updates already covered by the book are skipped at the start of the batch.
+        /// As with single updates, an update rejected for leaving a level negative
+        /// is skipped but consumes its sequence, and the first such error is
+        /// returned once the rest of the batch is applied.
+        pub fn apply_batch(&mut self, updates: Vec<LevelUpdate>) -> Result<(), BookError> {
+            let first_expected = self.sequence + 1;
+            if self.sync_state != SyncState::Synced {
+                return Err(BookError::SequenceGap {
+                    expected: first_expected,
+                    received: updates.first().map_or(first_expected, |update| update.sequence as u64),
+                });
+            }
+            let mut expected = first_expected;
+            let mut bids: BTreeMap<Price, Size> = BTreeMap::new();
+            let mut asks: BTreeMap<Price, Size> = BTreeMap::new();
+            let mut last_side = None;
+            let mut rejected = None;
+            for update in updates.iter() {
+                let received = update.sequence as u64;
+                if received < expected && expected == first_expected {
+                    continue;
+                }
+                if received < expected {
+                    return Err(BookError::StaleSequence { expected, received });
+                }
+                if received > expected {
+                    self.request_snapshot();
+                    return Err(BookError::SequenceGap { expected, received });
+                }
+                PendingUpdate::Level(update.clone()).validate(self.update_mode)?;
+                let order_type = to_order_type(update.side)?;
+                let price = to_decimal(update.price).ok_or_else(|| BookError::InvalidPrice(update.price.to_string()))?;
+                let size = to_decimal(update.size).ok_or_else(|| BookError::InvalidSize(update.size.to_string()))?;
+                let (changes, levels) = match order_type {
+                    OrderType::Bid => (&mut bids, &self.bids),
+                    OrderType::Ask => (&mut asks, &self.asks),
+                };
+                let updated = match self.update_mode {
+                    UpdateMode::Absolute => size,
+                    UpdateMode::Delta => {
+                        let current = changes
+                            .get(&price)
+                            .or_else(|| levels.get(&price).map(|level| &level.size))
+                            .cloned()
+                            .unwrap_or_else(BigDecimal::zero);
+                        current.add(size)
+                    }
+                };
+                if updated < BigDecimal::zero() && self.negative_level_policy != NegativeLevelPolicy::Remove {
+                    let error = BookError::NegativeLevel {
+                        price: to_plain_string(&price),
+                        size: to_plain_string(&updated),
+                    };
+                    if self.negative_level_policy == NegativeLevelPolicy::RequestSnapshot {
+                        self.request_snapshot();
+                        return Err(error);
+                    }
+                    rejected.get_or_insert(error);
+                    expected += 1;
+                    continue;
+                }
+                changes.insert(price, if updated < BigDecimal::zero() { BigDecimal::zero() } else { updated });
+                last_side = Some(order_type);
+                expected += 1;
+            }
+            if expected == first_expected {
+                return match updates.last() {
+                    None => Ok(()),
+                    Some(update) => Err(BookError::StaleSequence {
+                        expected,
+                        received: update.sequence as u64,
+                    }),
+                };
+            }
+            let sequence = expected - 1;
+            self.sequence = sequence;
+            for (order_type, changes) in [(OrderType::Bid, bids), (OrderType::Ask, asks)] {
+                for (price, size) in changes {
+                    if size.is_zero() {
+                        self.delete_level(order_type, price, sequence);
+                    } else {
+                        self.insert_level(order_type, price, size, sequence);
+                    }
+                }
+            }
+            if last_side.is_some() {
+                self.last_updated_side = last_side;
+            }
+            self.update_cross_state()?;
+            match rejected {
+                Some(error) => Err(error),
+                None => Ok(()),
+            }
+        }
+
         // This is update
         pub fn add_level(
             &mut self,
@@ -482,112 +1652,171 @@ pub mod book {
             price: f64,
             size: f64,
             sequence: u64,
-        ) -> bool {
+        ) -> Result<(), BookError> {
+            let price_decimal = to_decimal(price).ok_or_else(|| BookError::InvalidPrice(price.to_string()))?;
+            let size_decimal = to_decimal(size).ok_or_else(|| BookError::InvalidSize(size.to_string()))?;
+            self.add_level_decimal(order_type, price_decimal, size_decimal, sequence)
+        }
+
+        pub fn add_level_str(&mut self, order_type: OrderType, price: &str, size: &str, sequence: u64) -> Result<(), BookError> {
+            let level = Level::try_from(&DecimalPriceLevel {
+                price: price.to_string(),
+                size: size.to_string(),
+            })?;
+            self.add_level_decimal(order_type, level.price, level.size, sequence)
+        }
+
+        pub fn add_level_decimal(&mut self, order_type: OrderType, price: Price, size: Size, sequence: u64) -> Result<(), BookError> {
+            validate_level(&price, &size)?;
+            self.insert_level(order_type, price, size, sequence);
+            self.update_cross_state()
+        }
+
+        fn insert_level(&mut self, order_type: OrderType, price: Price, size: Size, sequence: u64) {
             self.sequence = sequence;
-            let group_size = self.group_size; // change this to your desired group size
+            self.last_updated_side = Some(order_type);
+            self.mark_consumers_dirty(order_type, &price);
+            let grouping = self.grouping; // change this to your desired group size
+            if !self.grouping_views.is_empty() {
+                let levels = match order_type {
+                    OrderType::Bid => &self.bids,
+                    OrderType::Ask => &self.asks,
+                };
+                let previous_size = levels.get(&price).map_or_else(BigDecimal::zero, |level| level.size.clone());
+                for view in self.grouping_views.values_mut() {
+                    view.apply(order_type, &price, &previous_size, &size);
+                }
+            }
+            if let Some(large_level_size) = &self.large_level_size {
+                let levels = match order_type {
+                    OrderType::Bid => &self.bids,
+                    OrderType::Ask => &self.asks,
+                };
+                let previous_size = levels.get(&price).map_or_else(BigDecimal::zero, |level| level.size.clone());
+                if &size >= large_level_size && &previous_size < large_level_size {
+                    self.large_level_events.push(LevelEvent {
+                        side: order_type,
+                        change: if previous_size.is_zero() { LevelChange::Add } else { LevelChange::Change },
+                        price: price.clone(),
+                        previous_size,
+                        size: size.clone(),
+                    });
+                    if self.large_level_events.len() > MAX_LARGE_LEVEL_EVENTS {
+                        self.large_level_events.remove(0);
+                    }
+                }
+            }
             match order_type {
                 OrderType::Bid => {
-                    self.bids_total = self.bids_total.clone().add(BigDecimal::from_f64(size).unwrap_or_default());
-                    self.bids_value_total = self
-                        .bids_value_total
-                        .clone()
-                        .add(BigDecimal::from_f64(size).unwrap_or_default().mul(BigDecimal::from_f64(price).unwrap_or_default()));
                     let current_size_at_level = self
                         .bids
-                        .get(&BigDecimal::from_f64(price).unwrap_or_default())
+                        .get(&price)
                         .map(|x| x.size.clone())
                         .unwrap_or(BigDecimal::zero());
-                    let price_decimal = BigDecimal::from_f64(price).unwrap_or_default();
-                    self.bids
-                        .insert(BigDecimal::from_f64(price).unwrap_or_default(), Level::new(price, size));
-                    let group_price = group(price_decimal, group_size, true);
+                    let size_change = (&size).sub(&current_size_at_level);
+                    self.bids_value_total = self.bids_value_total.clone().add((&size_change).mul(&price));
+                    self.bids_total = self.bids_total.clone().add(size_change);
+                    self.bids.insert(price.clone(), Level::from_decimal(price.clone(), size.clone()));
+                    let group_price = grouping.bucket(&price, true);
                     let bids_group = self
                         .grouped_bids
                         .entry(group_price)
                         .or_insert(BigDecimal::zero());
                     *bids_group -= current_size_at_level;
-                    *bids_group += BigDecimal::from_f64(size).unwrap_or_default();
+                    *bids_group += size;
                 }
                 OrderType::Ask => {
-                    self.asks_total = self.asks_total.clone().add(BigDecimal::from_f64(size).unwrap_or_default());
-                    self.asks_value_total = self
-                        .asks_value_total
-                        .clone()
-                        .add(BigDecimal::from_f64(size).unwrap_or_default().mul(BigDecimal::from_f64(price).unwrap_or_default()));
                     let current_size_at_level = self
                         .asks
-                        .get(&BigDecimal::from_f64(price).unwrap_or_default())
+                        .get(&price)
                         .map(|x| x.size.clone())
                         .unwrap_or(BigDecimal::zero());
-                    let price_decimal = BigDecimal::from_f64(price).unwrap_or_default();
-                    self.asks
-                        .insert(price_decimal.clone(), Level::new(price, size));
-                    let group_price = group(price_decimal, group_size, false);
+                    let size_change = (&size).sub(&current_size_at_level);
+                    self.asks_value_total = self.asks_value_total.clone().add((&size_change).mul(&price));
+                    self.asks_total = self.asks_total.clone().add(size_change);
+                    self.asks.insert(price.clone(), Level::from_decimal(price.clone(), size.clone()));
+                    let group_price = grouping.bucket(&price, false);
                     let asks_group = self
                         .grouped_asks
                         .entry(group_price)
                         .or_insert(BigDecimal::zero());
                     *asks_group -= current_size_at_level;
-                    *asks_group += BigDecimal::from_f64(size).unwrap_or_default();
+                    *asks_group += size;
                 }
             }
-            true
+            self.purge_beyond_depth(order_type);
+            self.audit_after_update();
+        }
+
+        pub fn remove_level(&mut self, order_type: OrderType, price: f64, sequence: u64) -> Result<(), BookError> {
+            let price_decimal = to_decimal(price).ok_or_else(|| BookError::InvalidPrice(price.to_string()))?;
+            self.remove_level_decimal(order_type, price_decimal, sequence)
         }
 
-        pub fn remove_level(&mut self, order_type: OrderType, price: f64, sequence: u64) -> bool {
+        pub fn remove_level_str(&mut self, order_type: OrderType, price: &str, sequence: u64) -> Result<(), BookError> {
+            let price_decimal = BigDecimal::from_str(price).map_err(|_| BookError::InvalidPrice(price.to_string()))?;
+            self.remove_level_decimal(order_type, price_decimal, sequence)
+        }
+
+        pub fn remove_level_decimal(&mut self, order_type: OrderType, price_decimal: Price, sequence: u64) -> Result<(), BookError> {
+            self.delete_level(order_type, price_decimal, sequence);
+            self.update_cross_state()
+        }
+
+        fn delete_level(&mut self, order_type: OrderType, price_decimal: Price, sequence: u64) {
             self.sequence = sequence;
-            let price_decimal = BigDecimal::from_f64(price).unwrap_or_default();
-            match order_type {
-                OrderType::Bid => {
-                    let removed_level = self.bids.remove(&price_decimal);
-                    if let Some(level) = removed_level {
-                        self.bids_total = self.bids_total.clone().sub(level.size.clone());
-                        self.bids_value_total = self.bids_value_total.clone().sub(level.value);
-                        let group_size = self.group_size; // replace with your desired group size
-                        let price_decimal = BigDecimal::from_f64(price).unwrap_or_default();
-                        let grouped_price = group(price_decimal, group_size, true);
-                        let removed_size = level.size;
-                        let grouped_level = self.grouped_bids.entry(grouped_price);
-                        match grouped_level {
-                            std::collections::btree_map::Entry::Vacant(_) => {}
-                            std::collections::btree_map::Entry::Occupied(level) => {
-                                let existing_size = level.get();
-                                if existing_size == &removed_size {
-                                    level.remove_entry();
-                                } else {
-                                    *level.into_mut() -= removed_size;
-                                }
-                            }
-                        }
+            self.mark_consumers_dirty(order_type, &price_decimal);
+            let grouping = self.grouping; // replace with your desired group size
+            if !self.grouping_views.is_empty() {
+                let levels = match order_type {
+                    OrderType::Bid => &self.bids,
+                    OrderType::Ask => &self.asks,
+                };
+                if let Some(previous_size) = levels.get(&price_decimal).map(|level| level.size.clone()) {
+                    for view in self.grouping_views.values_mut() {
+                        view.apply(order_type, &price_decimal, &previous_size, &BigDecimal::zero());
                     }
                 }
-                OrderType::Ask => {
-                    let removed_level = self.asks.remove(&price_decimal);
-                    if let Some(level) = removed_level {
-                        self.asks_total = self.asks_total.clone().sub(level.size.clone());
-                        self.asks_value_total = self.asks_value_total.clone().sub(level.value);
-
-                        let group_size = self.group_size; // replace with your desired group size
-                        let price_decimal = BigDecimal::from_f64(price).unwrap_or_default();
-                        let grouped_price = group(price_decimal, group_size, false);
-                        let removed_size = level.size;
-                        let grouped_level = self.grouped_asks.entry(grouped_price);
-                        match grouped_level {
-                            std::collections::btree_map::Entry::Vacant(_) => {}
-                            std::collections::btree_map::Entry::Occupied(level) => {
-                                let existing_size = level.get();
-                                if existing_size == &removed_size {
-                                    level.remove_entry();
-                                } else {
-                                    *level.into_mut() -= removed_size;
-                                }
-                            }
+            }
+            let (levels, total, value_total, grouped, group_lower) = match order_type {
+                OrderType::Bid => (&mut self.bids, &mut self.bids_total, &mut self.bids_value_total, &mut self.grouped_bids, true),
+                OrderType::Ask => (&mut self.asks, &mut self.asks_total, &mut self.asks_value_total, &mut self.grouped_asks, false),
+            };
+            let removed_level = levels.remove(&price_decimal);
+            if let Some(level) = removed_level {
+                *total = total.clone().sub(level.size.clone());
+                *value_total = value_total.clone().sub(level.value);
+                let grouped_price = grouping.bucket(&price_decimal, group_lower);
+                let removed_size = level.size;
+                let grouped_level = grouped.entry(grouped_price);
+                match grouped_level {
+                    std::collections::btree_map::Entry::Vacant(_) => {}
+                    std::collections::btree_map::Entry::Occupied(level) => {
+                        let existing_size = level.get();
+                        if existing_size == &removed_size {
+                            level.remove_entry();
+                        } else {
+                            *level.into_mut() -= removed_size;
                         }
                     }
                 }
             }
-            return true;
+            self.audit_after_update();
+        }
+
+        pub fn update_level_decimal(&mut self, level_message: DecimalLevelUpdate) -> Result<(), BookError> {
+            self.process_update(PendingUpdate::Decimal(level_message))
+        }
+
+        fn apply_level_decimal(&mut self, level_message: DecimalLevelUpdate) -> Result<(), BookError> {
+            let order_type = to_order_type(level_message.side)?;
+            let level = Level::try_from(&DecimalPriceLevel {
+                price: level_message.price,
+                size: level_message.size,
+            })?;
+            self.apply_side_update(order_type, level.price, level.size, level_message.sequence as u64)
         }
+
         pub fn get_levels(&self, count: i32) -> (Vec<Level>, Vec<Level>) {
             let asks = self
                 .asks
@@ -765,91 +1994,61 @@ pub mod book {
         }
 
         pub fn get_grouped_snapshot_new(&self, count: usize) -> OrderBookSnapshot {
-            let zero = BigDecimal::zero();
-            let two = BigDecimal::from_f32(2.0).unwrap();
-            let one = BigDecimal::from_f32(1.0).unwrap();
-            let percentage = BigDecimal::from_f32(10.0/100.0).unwrap();
-            let best_bid = self.grouped_bids.keys().rev().next().unwrap_or(&zero);
-            let best_ask = self.grouped_asks.keys().next().unwrap_or(&zero);
-            let mid_price = (best_bid + best_ask) / two;
-
-            let scale = value_to_scale(self.group_size);
-
-            let mid_price_lower = group(mid_price.clone(), self.group_size, true);
-            let mid_price_higher = group(mid_price.clone(), self.group_size, false);
-
-            let mut min_bid = mid_price.clone() * (one.clone() - percentage.clone());
-            let mut max_ask = mid_price.clone() * (one.clone() + percentage.clone());
-
-            let grouping_decimal = BigDecimal::from_f64(self.group_size).unwrap_or_default();
-
-            min_bid = group(min_bid, self.group_size, true);
-            max_ask = group(max_ask, self.group_size, false);
-
-            let mut bid_groups_to_include = Vec::with_capacity(count as usize);
-            let mut ask_groups_to_include = Vec::with_capacity(count as usize);
-
-            let mut bid = mid_price_lower;
-            let mut ask = mid_price_higher;
-            for _ in 0..count {
-                    bid = bid.with_scale_round(scale, RoundingMode::HalfUp);
-                    bid_groups_to_include.push(bid.clone());
-                    bid -= &grouping_decimal;
+            self.grouped_snapshot(&self.grouping, &self.grouped_bids, &self.grouped_asks, count)
+        }
 
-                    ask = ask.with_scale_round(scale, RoundingMode::HalfUp);
-                    ask_groups_to_include.push(ask.clone());
-                    ask += &grouping_decimal;
+        /// Appends the ladder of `get_grouped_snapshot_new` to `out` as
+        /// `price, size` pairs, the `count` asks from the best up then the
+        /// `count` bids from the best down. Returns how many of the ask and
+        /// bid buckets hold any size.
+        pub fn write_grouped_ladder(&self, count: usize, out: &mut Vec<f64>) -> (usize, usize) {
+            let (bid_prices, ask_prices) = grouped_ladder(&self.grouping, &self.grouped_bids, &self.grouped_asks, count);
+            let mut filled = [0, 0];
+            for (side, (prices, grouped)) in [(&ask_prices, &self.grouped_asks), (&bid_prices, &self.grouped_bids)].iter().enumerate() {
+                for price in prices.iter() {
+                    let level = grouped_level(grouped, price);
+                    if level.total_size != 0.0 {
+                        filled[side] += 1;
+                    }
+                    out.push(level.price);
+                    out.push(level.total_size);
+                }
             }
+            (filled[0], filled[1])
+        }
 
-            // while max_bid <= mid_price_lower {
-            //     bid_groups_to_include.push(max_bid.clone());
-            //     max_bid += grouping_decimal.clone();
-            //     max_bid = max_bid.with_scale_round(scale, RoundingMode::HalfUp);
-            // }
+        /// Mid the grouped ladder is centred on: halfway between the best bid
+        /// and ask buckets, with an empty side counting as 0.
+        pub fn grouped_mid_price(&self) -> f64 {
+            grouped_mid(&self.grouped_bids, &self.grouped_asks).to_f64().unwrap_or_default()
+        }
 
-            // while min_ask >= mid_price_higher {
-            //     ask_groups_to_include.push(min_ask.clone());
-            //     min_ask -= grouping_decimal.clone();
-            //     min_ask = min_ask.with_scale_round(scale, RoundingMode::HalfUp);
-            // }
+        /// Same as `get_grouped_snapshot_new` over the named grouping view.
+        pub fn get_view_grouped_snapshot(&self, name: &str, count: usize) -> Result<OrderBookSnapshot, BookError> {
+            let view = self
+                .grouping_views
+                .get(name)
+                .ok_or_else(|| BookError::UnknownView(name.to_string()))?;
+            Ok(self.grouped_snapshot(&view.strategy, &view.grouped_bids, &view.grouped_asks, count))
+        }
 
-            let bids = bid_groups_to_include.into_iter().rev()
-                .map(|price| {
-                    self.grouped_bids
-                        .get(&price)
-                        .map_or_else(|| SnapshotLevel {
-                            price: price.to_f64().unwrap(),
-                            total_size: 0.0,
-                            total_value: 0.0,
-                            relative_size: 0,
-                        }, |size| SnapshotLevel {
-                            price: price.to_f64().unwrap(),
-                            total_size: size.to_f64().unwrap_or(0.0),
-                            total_value: 0.0,
-                            relative_size: 0,
-                        })
-                })
-                .rev()
-                .take(count)
+        fn grouped_snapshot(
+            &self,
+            grouping: &GroupingStrategy,
+            grouped_bids: &BTreeMap<Price, Size>,
+            grouped_asks: &BTreeMap<Price, Size>,
+            count: usize,
+        ) -> OrderBookSnapshot {
+            let (bid_groups_to_include, ask_groups_to_include) = grouped_ladder(grouping, grouped_bids, grouped_asks, count);
+
+            let bids = bid_groups_to_include
+                .iter()
+                .map(|price| grouped_level(grouped_bids, price))
                 .collect::<Vec<SnapshotLevel>>();
 
-            let asks = ask_groups_to_include.into_iter()
-                .map(|price| {
-                    self.grouped_asks
-                        .get(&price)
-                        .map_or_else(|| SnapshotLevel {
-                            price: price.to_f64().unwrap(),
-                            total_size: 0.0,
-                            total_value: 0.0,
-                            relative_size: 0,
-                        }, |size| SnapshotLevel {
-                            price: price.to_f64().unwrap(),
-                            total_size: size.to_f64().unwrap_or(0.0),
-                            total_value: 0.0,
-                            relative_size: 0,
-                        })
-                })
-                .take(count)
+            let asks = ask_groups_to_include
+                .iter()
+                .map(|price| grouped_level(grouped_asks, price))
                 .collect::<Vec<SnapshotLevel>>();
 
             OrderBookSnapshot {
@@ -864,6 +2063,8 @@ pub mod book {
                     asks_value_total: self.asks_value_total.to_f64().unwrap_or(0.0),
                     spread: self.get_spread().to_string(),
                     sequence: self.sequence,
+                    cross_state: self.cross_state,
+                    depth_truncated: self.is_depth_truncated(),
                 },
                 cum_ask_values: Vec::new(),
                 cum_bid_values: Vec::new(),
@@ -871,7 +2072,7 @@ pub mod book {
         }
 
         pub fn get_grouped_snapshot(&self, count: usize) -> OrderBookSnapshot {
-            let group_size: f64 = self.group_size;
+            let grouping = self.grouping;
             let depth_map_percent = 0.0;
             let mut asks = self
                 .asks
@@ -880,7 +2081,7 @@ pub mod book {
                     let snapshot_level: SnapshotLevel = y.clone().into();
                     (x, snapshot_level)
                 })
-                .group_by(|level| group(level.0.clone(), group_size, false))
+                .group_by(|level| grouping.bucket(level.0, false))
                 .into_iter()
                 .map(|(grouped_price, grouped_levels)| {
                     let mut level = SnapshotLevel::new();
@@ -903,7 +2104,7 @@ pub mod book {
                     let snapshot_level: SnapshotLevel = y.clone().into();
                     (x, snapshot_level)
                 })
-                .group_by(|level| group(level.0.clone(), group_size, true))
+                .group_by(|level| grouping.bucket(level.0, true))
                 .into_iter()
                 .map(|(grouped_price, grouped_levels)| {
                     let mut level = SnapshotLevel::new();
@@ -959,6 +2160,8 @@ pub mod book {
                     asks_value_total: self.asks_value_total.to_f64().unwrap_or(0.0),
                     spread: spread.to_string(),
                     sequence: self.sequence,
+                    cross_state: self.cross_state,
+                    depth_truncated: self.is_depth_truncated(),
                 },
                 cum_ask_values: if depth_map_percent == 0.0 {
                     Vec::new()
@@ -980,15 +2183,18 @@ mod tests {
     use crate::book_utils::book::group;
 
     // Note this useful idiom: importing names from outer (for mod tests) scope.
-    use super::book::{Level, OrderBook, OrderType};
+    use super::book::{decode_level_updates, CrossPolicy, CrossState, DecimalLevelUpdate, DecimalPriceLevel, DecimalSnapshot, Level, LevelChange, NegativeLevelPolicy, OrderBook, OrderType, RangeUpdate, SnapshotLevel, SyncState, UpdateMode};
+    use crate::error::error::BookError;
     use bigdecimal::BigDecimal;
     use bytes::BytesMut;
     use num_traits::FromPrimitive;
     use num_traits::cast::ToPrimitive;
+    use num_traits::identities::Zero;
     use prost::Message;
     use std::convert::TryInto;
     use std::error::Error;
-    use stock_messages::stock_messages::SnapshotMessage;
+    use std::str::FromStr;
+    use stock_messages::stock_messages::{LevelUpdate, SnapshotMessage};
 
     fn create_asks(book: &mut OrderBook) {
         (100..200)
@@ -996,7 +2202,7 @@ mod tests {
             .zip((100..200).into_iter())
             .for_each(|(price, size)| {
                 let sequence = book.sequence + 1;
-                book.add_level(OrderType::Ask, price as f64, size as f64, sequence);
+                book.add_level(OrderType::Ask, price as f64, size as f64, sequence).unwrap();
             })
     }
 
@@ -1006,7 +2212,7 @@ mod tests {
             .zip((1..100).into_iter())
             .for_each(|(price, size)| {
                 let sequence = book.sequence + 1;
-                book.add_level(OrderType::Bid, price as f64, size as f64, sequence);
+                book.add_level(OrderType::Bid, price as f64, size as f64, sequence).unwrap();
             })
     }
 
@@ -1035,6 +2241,87 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_group_low_priced_snapshots() {
+        for instrument in ["Binance:ADA_BTC", "Binance:XVG_BTC", "Binance:TRX_BTC"] {
+            let bytes = std::fs::read(format!("snapshots/{}", instrument)).unwrap();
+            let mut book = OrderBook::from(SnapshotMessage::decode(bytes).unwrap());
+            for group_size in [1e-8, 5e-8, 1e-7] {
+                book.set_group_size(group_size);
+                assert!(book.audit().is_clean(), "{} by {}", instrument, group_size);
+                assert!(book.grouped_bids.len() > 1, "{} by {} collapsed", instrument, group_size);
+                let group_decimal = BigDecimal::from_str(&group_size.to_string()).unwrap();
+                for price in book.grouped_bids.keys().chain(book.grouped_asks.keys()) {
+                    assert!((price % &group_decimal).is_zero(), "{} is not a multiple of {}", price, group_size);
+                }
+                // keys keep the binary expansion of the f64 prices
+                let best_bid = book.bids.keys().next_back().unwrap().round(12);
+                let best_grouped_bid = book.grouped_bids.keys().next_back().unwrap();
+                assert!(best_grouped_bid <= &best_bid && &best_bid - best_grouped_bid < group_decimal, "{} {} by {}", instrument, best_bid, group_size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_grouped_snapshot_non_power_of_ten() {
+        let mut book = OrderBook::new("instrument", 0);
+        book.add_level_str(OrderType::Bid, "102.7", "1", 1).unwrap();
+        book.add_level_str(OrderType::Bid, "101", "2", 2).unwrap();
+        book.add_level_str(OrderType::Bid, "97.5", "4", 3).unwrap();
+        book.add_level_str(OrderType::Ask, "107.6", "1", 4).unwrap();
+        book.set_group_size(2.5);
+
+        let snapshot = book.get_grouped_snapshot_new(4);
+        let bids = snapshot.bids.iter().map(|level| (level.price, level.total_size)).collect::<Vec<(f64, f64)>>();
+        let asks = snapshot.asks.iter().map(|level| (level.price, level.total_size)).collect::<Vec<(f64, f64)>>();
+        assert_eq!(bids, vec![(105.0, 0.0), (102.5, 1.0), (100.0, 2.0), (97.5, 4.0)]);
+        assert_eq!(asks, vec![(107.5, 0.0), (110.0, 1.0), (112.5, 0.0), (115.0, 0.0)]);
+    }
+
+    #[test]
+    fn test_grouped_snapshot_changes() {
+        let mut book = OrderBook::new("instrument", 0);
+        book.add_level_str(OrderType::Bid, "102.7", "1", 1).unwrap();
+        book.add_level_str(OrderType::Bid, "101", "2", 2).unwrap();
+        book.add_level_str(OrderType::Ask, "107.6", "1", 3).unwrap();
+        book.set_group_size(2.5);
+        let consumer = book.register_snapshot_consumer(None).unwrap();
+        let levels = |levels: &Vec<SnapshotLevel>| levels.iter().map(|level| (level.price, level.total_size)).collect::<Vec<(f64, f64)>>();
+
+        let changes = book.get_grouped_snapshot_changes(consumer, 4).unwrap();
+        assert!(changes.full);
+        assert_eq!(changes.bids.len(), 4);
+        assert_eq!(changes.asks.len(), 4);
+
+        book.add_level_str(OrderType::Bid, "100.5", "3", 4).unwrap();
+        let changes = book.get_grouped_snapshot_changes(consumer, 4).unwrap();
+        assert!(!changes.full);
+        assert_eq!(levels(&changes.bids), vec![(100.0, 5.0)]);
+        assert!(changes.asks.is_empty());
+
+        let changes = book.get_grouped_snapshot_changes(consumer, 4).unwrap();
+        assert!(!changes.full && changes.bids.is_empty() && changes.asks.is_empty());
+
+        book.add_level_str(OrderType::Ask, "108", "2", 5).unwrap();
+        let changes = book.get_grouped_snapshot_changes(consumer, 4).unwrap();
+        assert!(!changes.full);
+        assert!(changes.bids.is_empty());
+        assert_eq!(levels(&changes.asks), vec![(110.0, 3.0)]);
+
+        book.add_level_str(OrderType::Ask, "113", "1", 6).unwrap();
+        book.remove_level_str(OrderType::Ask, "107.6", 7).unwrap();
+        book.remove_level_str(OrderType::Ask, "108", 8).unwrap();
+        assert!(book.get_grouped_snapshot_changes(consumer, 4).unwrap().full);
+
+        assert!(book.get_grouped_snapshot_changes(consumer, 5).unwrap().full);
+        book.set_group_size(5.0);
+        assert!(book.get_grouped_snapshot_changes(consumer, 5).unwrap().full);
+
+        assert!(book.register_snapshot_consumer(Some("missing")).is_err());
+        assert!(book.unregister_snapshot_consumer(consumer));
+        assert!(book.get_grouped_snapshot_changes(consumer, 4).is_err());
+    }
+
     #[test]
     fn test_create_book() {
         let mut book = OrderBook::new("instrument", 100);
@@ -1084,9 +2371,9 @@ mod tests {
 
         // ADD NEW LEVEL
         let mut sequence = book.sequence + 1;
-        book.add_level(OrderType::Bid, 99.1, 99.1, sequence);
+        book.add_level(OrderType::Bid, 99.1, 99.1, sequence).unwrap();
         sequence = book.sequence + 1;
-        book.add_level(OrderType::Ask, 99.9, 99.9, sequence);
+        book.add_level(OrderType::Ask, 99.9, 99.9, sequence).unwrap();
 
         let (first_ask, first_bid) = get_first_ask_and_bid(&book);
 
@@ -1097,9 +2384,9 @@ mod tests {
 
         // Update BID LEVEL
         sequence = book.sequence + 1;
-        book.add_level(OrderType::Bid, 99.1, 99.2, sequence);
+        book.add_level(OrderType::Bid, 99.1, 99.2, sequence).unwrap();
         sequence = book.sequence + 1;
-        book.add_level(OrderType::Ask, 99.9, 99.8, sequence);
+        book.add_level(OrderType::Ask, 99.9, 99.8, sequence).unwrap();
 
         let (first_ask, first_bid) = get_first_ask_and_bid(&book);
 
@@ -1110,9 +2397,9 @@ mod tests {
 
         // Remove LEVEL
         sequence = book.sequence + 1;
-        book.remove_level(OrderType::Bid, 99.1, sequence);
+        book.remove_level(OrderType::Bid, 99.1, sequence).unwrap();
         sequence = book.sequence + 1;
-        book.remove_level(OrderType::Ask, 99.9, sequence);
+        book.remove_level(OrderType::Ask, 99.9, sequence).unwrap();
 
         let (first_ask, first_bid) = get_first_ask_and_bid(&book);
 
@@ -1161,4 +2448,387 @@ mod tests {
         assert_eq!(bid_level[0].price.to_f64().unwrap(), 9015.85f64);
         assert_eq!(bid_level[0].size.to_f64().unwrap(), 0.027722000000000004f64);
     }
+
+    #[test]
+    fn test_decimal_levels() {
+        let snapshot = DecimalSnapshot {
+            instrument: "Binance:BTC/USDT".to_string(),
+            sequence: 10,
+            bids: vec![DecimalPriceLevel { price: "9015.85".to_string(), size: "0.02772200".to_string() }],
+            asks: vec![DecimalPriceLevel { price: "9017.78".to_string(), size: "0.170818".to_string() }],
+        };
+        let mut book: OrderBook = snapshot.clone().try_into().unwrap();
+        assert_eq!(book.bids_total, BigDecimal::from_str("0.027722").unwrap());
+
+        let output: DecimalSnapshot = (&book).into();
+        assert_eq!(output, snapshot);
+
+        assert!(book
+            .update_level_decimal(DecimalLevelUpdate {
+                side: 0,
+                price: "9015.86".to_string(),
+                size: "1.5".to_string(),
+                sequence: 11,
+            })
+            .is_ok());
+        assert!(book.add_level_str(OrderType::Ask, "9017.7", "0.000001", 12).is_ok());
+        assert_eq!(
+            book.add_level_str(OrderType::Ask, "9017.7x", "0.000001", 13),
+            Err(BookError::InvalidPrice("9017.7x".to_string()))
+        );
+
+        let (bids, asks) = book.get_levels(1);
+        assert_eq!(DecimalPriceLevel::from(&bids[0]), DecimalPriceLevel { price: "9015.86".to_string(), size: "1.5".to_string() });
+        assert_eq!(asks[0].value, BigDecimal::from_str("0.0090177").unwrap());
+    }
+
+    fn level_update(side: i32, price: f64, size: f64, sequence: i32) -> LevelUpdate {
+        LevelUpdate { r#type: 0, exchange: "".to_string(), price, product_id: "".to_string(), sequence, side, size, time: 0, count: 0 }
+    }
+
+    #[test]
+    fn test_gap_filled_out_of_order() {
+        let mut book = OrderBook::new("instrument", 100);
+        assert_eq!(book.update_level_message(level_update(0, 10.0, 1.0, 101)), Ok(()));
+        assert_eq!(
+            book.update_level_message(level_update(0, 11.0, 1.0, 103)),
+            Err(BookError::SequenceGap { expected: 102, received: 103 })
+        );
+        assert_eq!(book.sync_state(), SyncState::Gapped);
+        assert!(book.is_stale());
+        assert!(book.update_level_message(level_update(1, 13.0, 1.0, 104)).is_err());
+        assert_eq!(book.pending_update_count(), 2);
+
+        assert_eq!(book.update_level_message(level_update(1, 12.0, 1.0, 102)), Ok(()));
+        assert_eq!(book.sync_state(), SyncState::Synced);
+        assert_eq!(book.sequence, 104);
+        assert_eq!(book.pending_update_count(), 0);
+        assert_eq!(book.get_best_ask(), 12.0);
+        assert_eq!(book.get_best_bid(), 11.0);
+
+        let events = book.drain_sync_events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].state, SyncState::Gapped);
+        assert_eq!(events[1].state, SyncState::Synced);
+    }
+
+    #[test]
+    fn test_snapshot_drains_buffer() {
+        let bytes = std::fs::read("snapshots/Binance:BTC_USDT").unwrap();
+        let snapshot = SnapshotMessage::decode(bytes).unwrap();
+        let snapshot_sequence = snapshot.source_sequence;
+
+        let mut book = OrderBook::new("Binance:BTC/USDT", (snapshot_sequence - 10) as u64);
+        book.set_gap_tolerance(1);
+        book.set_group_size(10.0);
+        let _ = book.update_level_message(level_update(0, 9000.0, 1.0, snapshot_sequence));
+        let _ = book.update_level_message(level_update(0, 9016.0, 2.0, snapshot_sequence + 1));
+        let _ = book.update_level_message(level_update(1, 9017.0, 3.0, snapshot_sequence + 2));
+        assert_eq!(book.sync_state(), SyncState::AwaitingSnapshot);
+
+        let mut old_snapshot = snapshot.clone();
+        old_snapshot.source_sequence = snapshot_sequence - 5;
+        assert!(book.apply_snapshot(old_snapshot).is_err());
+        assert_eq!(book.sync_state(), SyncState::AwaitingSnapshot);
+
+        assert_eq!(book.apply_snapshot(snapshot), Ok(()));
+        assert_eq!(book.sync_state(), SyncState::Synced);
+        assert_eq!(book.sequence, (snapshot_sequence + 2) as u64);
+        assert_eq!(book.get_best_bid(), 9016.0);
+        assert_eq!(book.get_best_ask(), 9017.0);
+        assert!(book.grouped_bids.contains_key(&BigDecimal::from(9010)));
+
+        let states = book.drain_sync_events().iter().map(|event| event.state).collect::<Vec<SyncState>>();
+        assert_eq!(
+            states,
+            vec![SyncState::Gapped, SyncState::AwaitingSnapshot, SyncState::Resyncing, SyncState::AwaitingSnapshot, SyncState::Resyncing, SyncState::Synced]
+        );
+    }
+
+    #[test]
+    fn test_malformed_updates() {
+        let mut book = OrderBook::new("instrument", 100);
+        assert_eq!(book.update_level(vec![0xff, 0xff, 0xff]).map_err(|error| error.code()), Err(1));
+        assert_eq!(book.update_level_message(level_update(7, 10.0, 1.0, 101)), Err(BookError::UnknownSide(7)));
+        assert_eq!(
+            book.update_level_message(level_update(0, -1.0, 1.0, 101)),
+            Err(BookError::InvalidPrice("-1".to_string()))
+        );
+        assert_eq!(
+            book.update_level_message(level_update(0, 10.0, f64::NAN, 101)),
+            Err(BookError::InvalidSize("NaN".to_string()))
+        );
+        assert_eq!(book.sequence, 100);
+        assert_eq!(book.sync_state(), SyncState::Synced);
+
+        assert_eq!(book.update_level_message(level_update(0, 10.0, 1.0, 101)), Ok(()));
+        assert_eq!(
+            book.update_level_message(level_update(0, 10.0, 1.0, 101)),
+            Err(BookError::StaleSequence { expected: 102, received: 101 })
+        );
+        assert_eq!(book.add_level(OrderType::Bid, 10.0, -2.0, 102), Err(BookError::InvalidSize("-2".to_string())));
+        assert_eq!(book.get_best_bid(), 10.0);
+    }
+
+    #[test]
+    fn test_delete_only_named_side() {
+        let mut book = OrderBook::new("instrument", 100);
+        book.add_level(OrderType::Bid, 10.0, 1.0, 100).unwrap();
+        book.add_level(OrderType::Ask, 10.0, 2.0, 100).unwrap();
+        assert_eq!(book.update_level_message(level_update(1, 10.0, 0.0, 101)), Ok(()));
+        assert!(book.asks.is_empty());
+        assert_eq!(book.get_best_bid(), 10.0);
+        assert_eq!(book.bids_total, BigDecimal::from(1));
+        assert!(book.audit().is_clean());
+    }
+
+    #[test]
+    fn test_delta_updates() {
+        let mut book = OrderBook::new("instrument", 100);
+        book.set_update_mode(UpdateMode::Delta);
+        assert_eq!(book.update_level_message(level_update(0, 10.0, 1.5, 101)), Ok(()));
+        assert_eq!(book.update_level_message(level_update(0, 10.0, 1.0, 102)), Ok(()));
+        assert_eq!(book.update_level_message(level_update(0, 10.0, -0.5, 103)), Ok(()));
+        assert_eq!(book.bids[&BigDecimal::from(10)].size, BigDecimal::from(2));
+
+        assert_eq!(
+            book.update_level_message(level_update(0, 10.0, -3.0, 104)),
+            Err(BookError::NegativeLevel { price: "10".to_string(), size: "-1.0".to_string() })
+        );
+        assert_eq!(book.sequence, 104);
+        assert_eq!(book.bids[&BigDecimal::from(10)].size, BigDecimal::from(2));
+
+        book.set_negative_level_policy(NegativeLevelPolicy::Remove);
+        assert_eq!(book.update_level_message(level_update(0, 10.0, -3.0, 105)), Ok(()));
+        assert!(book.bids.is_empty());
+        assert!(book.audit().is_clean());
+
+        book.set_negative_level_policy(NegativeLevelPolicy::RequestSnapshot);
+        assert!(book.update_level_message(level_update(1, 11.0, -1.0, 106)).is_err());
+        assert_eq!(book.sync_state(), SyncState::AwaitingSnapshot);
+    }
+
+    #[test]
+    fn test_delta_range_update() {
+        let mut book = OrderBook::new("instrument", 100);
+        book.set_update_mode(UpdateMode::Delta);
+        book.add_level(OrderType::Bid, 10.0, 2.0, 100).unwrap();
+        let range_update = |first_sequence, last_sequence, bids| RangeUpdate {
+            first_sequence,
+            last_sequence,
+            bids: decimal_levels(bids),
+            asks: vec![],
+            checksum: None,
+        };
+
+        // only the change taking 10 below zero is skipped, as if sent on its own
+        assert_eq!(
+            book.update_range(range_update(101, 102, vec![("10", "-1.5"), ("9", "1"), ("10", "-1")])),
+            Err(BookError::NegativeLevel { price: "10".to_string(), size: "-0.5".to_string() })
+        );
+        assert_eq!(book.sequence, 102);
+        assert_eq!(book.sync_state(), SyncState::Synced);
+        assert_eq!(book.bids[&BigDecimal::from(10)].size, BigDecimal::from_str("0.5").unwrap());
+        assert_eq!(book.bids[&BigDecimal::from(9)].size, BigDecimal::from(1));
+
+        assert_eq!(book.update_range(range_update(103, 104, vec![("10", "-0.25"), ("10", "1")])), Ok(()));
+        assert_eq!(book.sequence, 104);
+        assert_eq!(book.bids[&BigDecimal::from(10)].size, BigDecimal::from_str("1.25").unwrap());
+        assert!(book.audit().is_clean());
+    }
+
+    #[test]
+    fn test_cross_detection() {
+        let mut book = OrderBook::new("instrument", 100);
+        book.add_level(OrderType::Bid, 10.0, 1.0, 100).unwrap();
+        book.add_level(OrderType::Ask, 11.0, 1.0, 100).unwrap();
+        book.add_level(OrderType::Ask, 12.0, 1.0, 100).unwrap();
+        assert_eq!(book.cross_state(), CrossState::Normal);
+
+        assert_eq!(book.update_level_message(level_update(0, 11.0, 2.0, 101)), Ok(()));
+        assert_eq!(book.cross_state(), CrossState::Locked);
+        assert_eq!(book.update_level_message(level_update(0, 11.5, 2.0, 102)), Ok(()));
+        assert_eq!(book.cross_state(), CrossState::Crossed);
+        assert_eq!(book.get_grouped_snapshot_new(5).info.cross_state, CrossState::Crossed);
+
+        // the asks at 11 and 11.5 were left behind, the bids are newer
+        book.set_cross_policy(CrossPolicy::DropOlderSide);
+        assert_eq!(book.update_level_message(level_update(0, 9.0, 1.0, 103)), Ok(()));
+        assert_eq!(book.cross_state(), CrossState::Normal);
+        assert_eq!(book.get_best_ask(), 12.0);
+        assert_eq!(book.get_best_bid(), 11.5);
+        assert!(book.audit().is_clean());
+
+        book.set_cross_policy(CrossPolicy::Resync);
+        assert_eq!(
+            book.update_level_message(level_update(1, 11.0, 1.0, 104)),
+            Err(BookError::CrossedBook { best_bid: 11.5, best_ask: 11.0 })
+        );
+        assert_eq!(book.sync_state(), SyncState::AwaitingSnapshot);
+    }
+
+    #[test]
+    fn test_apply_batch() {
+        let mut book = OrderBook::new("instrument", 100);
+        book.add_level(OrderType::Bid, 10.0, 1.0, 100).unwrap();
+        let batch = vec![
+            level_update(0, 10.0, 3.0, 99),
+            level_update(0, 10.0, 2.0, 101),
+            level_update(1, 12.0, 1.0, 102),
+            level_update(0, 9.0, 4.0, 103),
+            level_update(1, 12.0, 0.0, 104),
+            level_update(1, 13.0, 5.0, 105),
+        ];
+        let mut bytes = Vec::new();
+        for update in batch.iter() {
+            update.encode_length_delimited(&mut bytes).unwrap();
+        }
+        assert_eq!(decode_level_updates(&bytes).unwrap(), batch);
+        assert_eq!(book.update_level_batch(bytes), Ok(()));
+        assert_eq!(book.sequence, 105);
+        assert_eq!(book.bids_total, BigDecimal::from(6));
+        assert_eq!(book.get_best_ask(), 13.0);
+        assert!(book.audit().is_clean());
+
+        assert_eq!(
+            book.apply_batch(vec![level_update(0, 11.0, 1.0, 105)]),
+            Err(BookError::StaleSequence { expected: 106, received: 105 })
+        );
+
+        // a gap in the middle leaves the levels untouched and asks for a snapshot
+        let before = book.clone();
+        let batch = vec![level_update(0, 11.0, 1.0, 106), level_update(0, 8.0, 1.0, 108)];
+        assert_eq!(book.apply_batch(batch), Err(BookError::SequenceGap { expected: 107, received: 108 }));
+        assert_eq!(book.sequence, 105);
+        assert_eq!(book.bids, before.bids);
+        assert_eq!(book.grouped_bids, before.grouped_bids);
+        assert_eq!(book.sync_state(), SyncState::AwaitingSnapshot);
+        assert!(book.is_stale());
+        assert_eq!(book.drain_sync_events().last().map(|event| event.state), Some(SyncState::AwaitingSnapshot));
+        assert!(decode_level_updates(&[0x05, 0x08]).is_err());
+        let oversized = vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert_eq!(book.update_level_batch(oversized).map_err(|error| error.code()), Err(1));
+    }
+
+    #[test]
+    fn test_apply_delta_batch_rejects_negative_level() {
+        let mut book = OrderBook::new("instrument", 100);
+        book.set_update_mode(UpdateMode::Delta);
+        book.add_level(OrderType::Bid, 10.0, 1.0, 100).unwrap();
+        let batch = vec![level_update(0, 10.0, -2.0, 101), level_update(0, 9.0, 1.0, 102)];
+        assert_eq!(
+            book.apply_batch(batch),
+            Err(BookError::NegativeLevel { price: "10".to_string(), size: "-1".to_string() })
+        );
+        // the rejected update still consumes its sequence, so the feed carries on
+        assert_eq!(book.sequence, 102);
+        assert_eq!(book.sync_state(), SyncState::Synced);
+        assert_eq!(book.bids[&BigDecimal::from(10)].size, BigDecimal::from(1));
+        assert_eq!(book.bids[&BigDecimal::from(9)].size, BigDecimal::from(1));
+        assert_eq!(book.update_level_message(level_update(0, 10.0, 1.0, 103)), Ok(()));
+        assert_eq!(book.bids[&BigDecimal::from(10)].size, BigDecimal::from(2));
+
+        assert!(book.apply_batch(vec![level_update(0, 8.0, -1.0, 104)]).is_err());
+        assert_eq!(book.sequence, 104);
+        assert!(book.audit().is_clean());
+    }
+
+    #[test]
+    fn test_max_depth_purges_window() {
+        let mut book = OrderBook::new("instrument", 0);
+        create_bids(&mut book);
+        create_asks(&mut book);
+        book.set_max_depth(Some(3));
+        assert_eq!(book.bids.len(), 3);
+        assert_eq!(book.asks.len(), 3);
+        assert_eq!(book.bids_total, BigDecimal::from(99 + 98 + 97));
+        assert_eq!(book.get_best_bid(), 99.0);
+
+        let sequence = book.sequence;
+        book.add_level(OrderType::Ask, 99.5, 1.0, sequence + 1).unwrap();
+        book.add_level(OrderType::Ask, 150.0, 1.0, sequence + 2).unwrap();
+        assert_eq!(book.asks.keys().map(|price| price.to_f64().unwrap()).collect::<Vec<f64>>(), vec![99.5, 100.0, 101.0]);
+        assert_eq!(book.asks_total, BigDecimal::from(202));
+        assert!(book.audit().is_clean());
+        assert!(book.get_grouped_snapshot_new(5).info.depth_truncated);
+    }
+
+    #[test]
+    fn test_shallow_book_is_not_truncated() {
+        let mut book = OrderBook::new("instrument", 0);
+        book.add_level(OrderType::Bid, 99.0, 1.0, 1).unwrap();
+        book.add_level(OrderType::Bid, 98.0, 1.0, 2).unwrap();
+        book.add_level(OrderType::Ask, 100.0, 1.0, 3).unwrap();
+        book.set_max_depth(Some(20));
+        assert!(!book.is_depth_truncated());
+        assert!(!book.get_grouped_snapshot_new(5).info.depth_truncated);
+
+        book.set_max_depth(Some(2));
+        assert!(book.is_depth_truncated());
+        book.set_max_depth(Some(1));
+        book.set_max_depth(Some(20));
+        // the purged bid is still missing
+        assert!(book.is_depth_truncated());
+    }
+
+    fn decimal_levels(levels: Vec<(&str, &str)>) -> Vec<DecimalPriceLevel> {
+        levels
+            .iter()
+            .map(|(price, size)| DecimalPriceLevel { price: price.to_string(), size: size.to_string() })
+            .collect()
+    }
+
+    #[test]
+    fn test_replace_top_levels() {
+        let mut book = OrderBook::new("instrument", 0);
+        create_bids(&mut book);
+        create_asks(&mut book);
+        book.set_group_size(10.0);
+        let sequence = book.sequence;
+
+        let snapshot = DecimalSnapshot {
+            instrument: "instrument".to_string(),
+            sequence: sequence + 1,
+            bids: decimal_levels(vec![("99", "99"), ("98.5", "1"), ("97", "5")]),
+            asks: decimal_levels(vec![("100", "100"), ("102", "102"), ("103", "103")]),
+        };
+        let events = book.replace_top_levels(snapshot, 3).unwrap();
+        let summary = events
+            .iter()
+            .map(|event| (event.side, event.change, event.price.to_f64().unwrap()))
+            .collect::<Vec<(OrderType, LevelChange, f64)>>();
+        assert_eq!(
+            summary,
+            vec![
+                (OrderType::Bid, LevelChange::Delete, 98.0),
+                (OrderType::Bid, LevelChange::Change, 97.0),
+                (OrderType::Bid, LevelChange::Add, 98.5),
+                (OrderType::Ask, LevelChange::Delete, 101.0),
+            ]
+        );
+        assert_eq!(events[1].previous_size, BigDecimal::from(97));
+        assert_eq!(book.sequence, sequence + 1);
+        assert_eq!(book.get_group_size(), 10.0);
+        assert_eq!(book.bids.len(), 99);
+        assert_eq!(book.asks.len(), 99);
+        assert!(book.audit().is_clean());
+
+        // a short side means there is nothing behind it
+        let snapshot = DecimalSnapshot {
+            instrument: "instrument".to_string(),
+            sequence: 0,
+            bids: decimal_levels(vec![("99", "99")]),
+            asks: vec![],
+        };
+        let events = book.replace_top_levels(snapshot, 3).unwrap();
+        assert_eq!(events.len(), 98 + 99);
+        assert_eq!(book.bids.len(), 1);
+        assert!(book.asks.is_empty());
+        assert_eq!(book.sequence, sequence + 1);
+        assert!(book.audit().is_clean());
+
+        let snapshot = DecimalSnapshot { instrument: "instrument".to_string(), sequence: 0, bids: vec![], asks: vec![] };
+        assert_eq!(book.replace_top_levels(snapshot, 0).unwrap_err().code(), 15);
+        assert_eq!(book.bids.len(), 1);
+    }
 }
@@ -0,0 +1,589 @@
+pub mod book_handle {
+    use crate::book::book::{CrossPolicy, NegativeLevelPolicy, OrderBook, OrderBookSnapshot, SnapshotLevel, UpdateMode};
+    use crate::checksum::checksum::ChecksumAlgorithm;
+    use crate::consumer::consumer::GroupedSnapshotChanges;
+    use crate::error::error::BookError;
+    use crate::grouping::grouping::{GroupingStrategy, NiceSequence};
+    use crate::memory::memory::BookMemory;
+    use crate::subscriptions::subscriptions::{BookEvent, BookEventKind, BookWatcher};
+    use js_sys::{Float64Array, Function, Object, Reflect};
+    use num_traits::ToPrimitive;
+    use prost::Message;
+    use std::collections::BTreeMap;
+    use stock_messages::stock_messages::SnapshotMessage;
+    use wasm_bindgen::prelude::*;
+
+    /// Entries before the levels in the ladder buffer: the number of ask and
+    /// bid buckets holding any size, the mid the buckets are centred on, the
+    /// bucket width (0 for logarithmic grouping) and the sequence. The ladder
+    /// always has the requested count of buckets per side.
+    pub const LADDER_HEADER_LEN: usize = 5;
+
+    /// An order book owned by JS as an `OrderBook` object. Its memory is
+    /// released when JS calls `free()`.
+    #[wasm_bindgen(js_name = OrderBook)]
+    pub struct BookHandle {
+        pub(crate) book: OrderBook,
+        // written by `grouped_ladder`, kept between calls so its allocation is reused
+        ladder: Vec<f64>,
+        callbacks: BTreeMap<BookEventKind, Function>,
+        watcher: BookWatcher,
+        /// Set for books kept in the id-based map: due callbacks are queued
+        /// instead of called, and run once the map is no longer borrowed.
+        pub(crate) defer_callbacks: bool,
+        deferred_calls: Vec<(Function, BookEvent)>,
+    }
+
+    #[wasm_bindgen(js_class = OrderBook)]
+    impl BookHandle {
+        #[wasm_bindgen(constructor)]
+        pub fn new(instrument: &str) -> BookHandle {
+            BookHandle::from(OrderBook::new(instrument, 0))
+        }
+
+        /// Throws if the snapshot cannot be decoded.
+        pub fn from_snapshot(bytes: Vec<u8>) -> Result<BookHandle, JsValue> {
+            let snapshot = SnapshotMessage::decode(bytes).map_err(BookError::from)?;
+            Ok(BookHandle::from(OrderBook::from(snapshot)))
+        }
+
+        #[wasm_bindgen(getter)]
+        pub fn instrument(&self) -> String {
+            self.book.instrument.clone()
+        }
+
+        /// The sequence as a number, exact up to 2^53.
+        #[wasm_bindgen(getter)]
+        pub fn sequence(&self) -> f64 {
+            self.book.sequence as f64
+        }
+
+        /// Throws if the snapshot cannot be decoded, or if it does not reach the
+        /// buffered updates.
+        pub fn update_snapshot(&mut self, bytes: Vec<u8>) -> Result<(), JsValue> {
+            let snapshot = SnapshotMessage::decode(bytes).map_err(BookError::from)?;
+            let result = self.book.apply_snapshot(snapshot);
+            self.finish_update(result)
+        }
+
+        /// Replaces the top `depth` levels with a partial snapshot. Returns the
+        /// implied level events flattened as `[side, change, price, size, ...]`
+        /// with side Bid = 1 / Ask = 2 and change Add = 0 / Change = 1 / Delete = 2.
+        pub fn update_partial_snapshot(&mut self, bytes: Vec<u8>, depth: usize) -> Result<Vec<f64>, JsValue> {
+            let snapshot = SnapshotMessage::decode(bytes).map_err(BookError::from)?;
+            self.replace_top_levels(snapshot, depth)
+        }
+
+        /// Throws on a malformed frame or a sequence gap. Returns false if the
+        /// update is stale and was ignored.
+        pub fn update_level(&mut self, bytes: Vec<u8>) -> Result<bool, JsValue> {
+            let result = match self.book.update_level(bytes) {
+                Ok(()) => Ok(true),
+                Err(BookError::StaleSequence { .. }) => Ok(false),
+                Err(error) => Err(error),
+            };
+            self.finish_update(result)
+        }
+
+        /// Applies a length-delimited stream of `LevelUpdate`s. Nothing is
+        /// applied if any update is malformed or out of sequence.
+        pub fn update_levels(&mut self, bytes: Vec<u8>) -> Result<(), JsValue> {
+            let result = self.book.update_level_batch(bytes);
+            self.finish_update(result)
+        }
+
+        /// The `SyncState` as its index (Synced = 0, Gapped = 1,
+        /// AwaitingSnapshot = 2, Resyncing = 3).
+        pub fn sync_state(&self) -> u32 {
+            self.book.sync_state() as u32
+        }
+
+        pub fn is_stale(&self) -> bool {
+            self.book.is_stale()
+        }
+
+        /// The `CrossState` as its index (Normal = 0, Locked = 1, Crossed = 2).
+        pub fn cross_state(&self) -> u32 {
+            self.book.cross_state() as u32
+        }
+
+        /// The book encoded as a `SnapshotMessage`.
+        pub fn snapshot(&self) -> Vec<u8> {
+            (&self.book).into()
+        }
+
+        /// Grouped ladder flattened as asks from the top down, a
+        /// `99999.99999` pair, then bids, each level as `price, size`.
+        pub fn grouped_snapshot(&self, count: usize) -> Vec<f64> {
+            flatten_grouped_snapshot(&self.book.get_grouped_snapshot_new(count))
+        }
+
+        pub fn set_group_size(&mut self, size: f64) -> Result<(), JsValue> {
+            if !(size.is_finite() && size > 0.0) {
+                return Err(BookError::InvalidSize(size.to_string()).into());
+            }
+            self.book.set_group_size(size);
+            Ok(())
+        }
+
+        /// Sets how the default grouping buckets prices. `strategy` is the
+        /// index of `GroupingStrategy` (Absolute = 0 with `value` as the group
+        /// size, BasisPoints = 1 with `value` basis points of `reference`,
+        /// Logarithmic = 2 with `value` buckets per decade).
+        pub fn set_grouping_strategy(&mut self, strategy: u32, value: f64, reference: f64) -> Result<(), JsValue> {
+            let strategy = to_grouping_strategy(strategy, value, reference)?;
+            self.book.set_grouping_strategy(strategy)?;
+            Ok(())
+        }
+
+        /// Adds or replaces a named grouping view kept up to date with the book.
+        pub fn add_grouping_view(&mut self, name: &str, group_size: f64) -> Result<(), JsValue> {
+            self.add_grouping_view_strategy(name, 0, group_size, 0.0)
+        }
+
+        /// Like `add_grouping_view` with a strategy, see `set_grouping_strategy`.
+        pub fn add_grouping_view_strategy(&mut self, name: &str, strategy: u32, value: f64, reference: f64) -> Result<(), JsValue> {
+            let strategy = to_grouping_strategy(strategy, value, reference)?;
+            self.book.add_grouping_view(name, strategy)?;
+            Ok(())
+        }
+
+        /// Returns false if the book has no view with this name.
+        pub fn remove_grouping_view(&mut self, name: &str) -> bool {
+            self.book.remove_grouping_view(name)
+        }
+
+        /// Writes the grouped ladder into the book's ladder buffer and returns a
+        /// view on it: the `LADDER_HEADER_LEN` header (filled ask buckets,
+        /// filled bid buckets, mid, bucket width, sequence), then `count`
+        /// `price, size` pairs for the asks from the best up and `count` for
+        /// the bids from the best down, empty buckets having a size of 0. The
+        /// view aliases wasm memory, it is overwritten by the next call and
+        /// detached if the memory grows, so read it right away or `slice()` it.
+        /// Freeing the book invalidates the view without detaching it, it then
+        /// reads whatever reuses the memory.
+        pub fn grouped_ladder(&mut self, count: usize) -> Float64Array {
+            self.write_ladder(count);
+            unsafe { Float64Array::view(&self.ladder) }
+        }
+
+        /// Grouped snapshot of a named view, laid out like `grouped_snapshot`.
+        pub fn view_grouped_snapshot(&self, name: &str, count: usize) -> Result<Vec<f64>, JsValue> {
+            Ok(flatten_grouped_snapshot(&self.book.get_view_grouped_snapshot(name, count)?))
+        }
+
+        /// Registers a reader of `grouped_snapshot_changes`, over the named
+        /// grouping view or the default grouping if `view` is undefined.
+        pub fn register_snapshot_consumer(&mut self, view: Option<String>) -> Result<u32, JsValue> {
+            Ok(self.book.register_snapshot_consumer(view.as_deref())?)
+        }
+
+        pub fn unregister_snapshot_consumer(&mut self, consumer: u32) -> bool {
+            self.book.unregister_snapshot_consumer(consumer)
+        }
+
+        /// Grouped levels changed since the consumer's previous call, laid out
+        /// like `grouped_snapshot` after a leading flag that is 1 when the whole
+        /// ladder is included and replaces the previous one, 0 when only the
+        /// changed buckets are.
+        pub fn grouped_snapshot_changes(&mut self, consumer: u32, count: usize) -> Result<Vec<f64>, JsValue> {
+            let changes = self.book.get_grouped_snapshot_changes(consumer, count)?;
+            Ok(flatten_grouped_changes(&changes))
+        }
+
+        /// 0 clears the tick size, which is then inferred from the levels.
+        pub fn set_tick_size(&mut self, tick_size: f64) -> Result<(), JsValue> {
+            if !(tick_size.is_finite() && tick_size >= 0.0) {
+                return Err(BookError::InvalidSize(tick_size.to_string()).into());
+            }
+            self.book.set_tick_size(if tick_size == 0.0 { None } else { Some(tick_size) });
+            Ok(())
+        }
+
+        /// Group sizes to offer, from the tick size up to `max_percent` of mid.
+        /// `sequence` is the index of `NiceSequence` (1-2-5 = 0, 1-2.5-5 = 1).
+        pub fn suggest_group_sizes(&self, max_percent: f64, sequence: u32) -> Result<Vec<f64>, JsValue> {
+            let sequence = to_nice_sequence(sequence)?;
+            Ok(self.book.suggest_group_sizes(max_percent, sequence))
+        }
+
+        /// The suggested group size giving about `buckets` non-empty groups
+        /// within `percent` of mid, or 0 if the book is empty.
+        pub fn group_size_for_buckets(&self, buckets: usize, percent: f64, sequence: u32) -> Result<f64, JsValue> {
+            let sequence = to_nice_sequence(sequence)?;
+            Ok(self.book.group_size_for_buckets(buckets, percent, sequence).unwrap_or(0.0))
+        }
+
+        /// Switches between absolute sizes (`delta = false`) and signed size
+        /// deltas (`delta = true`).
+        pub fn set_update_mode(&mut self, delta: bool) {
+            self.book.set_update_mode(if delta { UpdateMode::Delta } else { UpdateMode::Absolute });
+        }
+
+        /// `policy` is the index of `NegativeLevelPolicy` (Remove = 0,
+        /// Reject = 1, RequestSnapshot = 2).
+        pub fn set_negative_level_policy(&mut self, policy: u32) -> Result<(), JsValue> {
+            let policy = match policy {
+                0 => NegativeLevelPolicy::Remove,
+                1 => NegativeLevelPolicy::Reject,
+                2 => NegativeLevelPolicy::RequestSnapshot,
+                _ => return Err(BookError::InvalidArgument(format!("unknown negative level policy {}", policy)).into()),
+            };
+            self.book.set_negative_level_policy(policy);
+            Ok(())
+        }
+
+        /// `policy` is the index of `CrossPolicy` (FlagOnly = 0,
+        /// DropOlderSide = 1, Resync = 2).
+        pub fn set_cross_policy(&mut self, policy: u32) -> Result<(), JsValue> {
+            let policy = match policy {
+                0 => CrossPolicy::FlagOnly,
+                1 => CrossPolicy::DropOlderSide,
+                2 => CrossPolicy::Resync,
+                _ => return Err(BookError::InvalidArgument(format!("unknown cross policy {}", policy)).into()),
+            };
+            self.book.set_cross_policy(policy);
+            Ok(())
+        }
+
+        /// `algorithm` is the index of `ChecksumAlgorithm` (Okx = 0, Kraken = 1,
+        /// Bitfinex = 2), or -1 to stop verifying checksums.
+        pub fn set_checksum_algorithm(&mut self, algorithm: i32) -> Result<(), JsValue> {
+            let algorithm = match algorithm {
+                -1 => None,
+                0 => Some(ChecksumAlgorithm::Okx),
+                1 => Some(ChecksumAlgorithm::Kraken),
+                2 => Some(ChecksumAlgorithm::Bitfinex),
+                _ => return Err(BookError::InvalidArgument(format!("unknown checksum algorithm {}", algorithm)).into()),
+            };
+            self.book.set_checksum_algorithm(algorithm);
+            Ok(())
+        }
+
+        /// Verifies the checksum sent by the venue after an update. Throws and
+        /// marks the book as awaiting a snapshot on a mismatch.
+        pub fn verify_checksum(&mut self, checksum: f64) -> Result<(), JsValue> {
+            self.book.verify_checksum(checksum as i64)?;
+            Ok(())
+        }
+
+        /// Limits each side to `max_depth` levels, 0 removes the limit.
+        pub fn set_max_depth(&mut self, max_depth: usize) {
+            self.book.set_max_depth(if max_depth == 0 { None } else { Some(max_depth) });
+        }
+
+        /// Calls `callback` on events found while applying updates and
+        /// snapshots, replacing any previous callback for the kind. `kind` is
+        /// the index of `BookEventKind`, and the callback gets
+        /// `(best_bid, best_ask)` for BestPrice = 0, `(spread)` for Spread = 1,
+        /// `(sequence)` for SequenceGap = 2 and ResyncNeeded = 3, and
+        /// `(side, price, size)` for LargeLevel = 4. Callbacks run inside the
+        /// update call, wasm-bindgen throws if one uses this object before the
+        /// update returns, so defer with `queueMicrotask` to read it.
+        pub fn subscribe(&mut self, kind: u32, callback: Function) -> Result<(), JsValue> {
+            let kind = to_event_kind(kind)?;
+            if self.callbacks.is_empty() {
+                self.watcher.reset(&mut self.book);
+            }
+            self.callbacks.insert(kind, callback);
+            Ok(())
+        }
+
+        /// Returns false if nothing was subscribed to this kind.
+        pub fn unsubscribe(&mut self, kind: u32) -> Result<bool, JsValue> {
+            let kind = to_event_kind(kind)?;
+            Ok(self.callbacks.remove(&kind).is_some())
+        }
+
+        /// Fires each kind of event at most once per `interval` milliseconds.
+        /// Held back events fire with the first update after the interval, or
+        /// from `flush_events`: the latest best price and spread, and every
+        /// sequence gap, resync and large level event. 0 fires every event.
+        pub fn set_event_throttle(&mut self, interval: f64) -> Result<(), JsValue> {
+            if !(interval.is_finite() && interval >= 0.0) {
+                return Err(BookError::InvalidArgument(format!("invalid event throttle {}", interval)).into());
+            }
+            self.watcher.interval = interval;
+            Ok(())
+        }
+
+        /// Levels reaching `size` fire LargeLevel events, 0 disables them.
+        pub fn set_large_level_size(&mut self, size: f64) -> Result<(), JsValue> {
+            if !(size.is_finite() && size >= 0.0) {
+                return Err(BookError::InvalidSize(size.to_string()).into());
+            }
+            self.book.set_large_level_size(if size == 0.0 { None } else { Some(size) });
+            Ok(())
+        }
+
+        /// Approximate memory held by the book, as an object with the counts
+        /// `bidLevels`, `askLevels`, `groupedBidLevels`, `groupedAskLevels`,
+        /// `viewLevels`, `pendingUpdates`, `queuedEvents` and `dirtyBuckets`, and the estimate
+        /// `bytes` which includes the ladder buffer.
+        pub fn memory_usage(&self) -> Result<Object, JsValue> {
+            let memory = self.book_memory();
+            to_object(&[
+                ("bidLevels", memory.bid_levels.into()),
+                ("askLevels", memory.ask_levels.into()),
+                ("groupedBidLevels", memory.grouped_bid_levels.into()),
+                ("groupedAskLevels", memory.grouped_ask_levels.into()),
+                ("viewLevels", memory.view_levels.into()),
+                ("pendingUpdates", memory.pending_updates.into()),
+                ("queuedEvents", memory.queued_events.into()),
+                ("dirtyBuckets", memory.dirty_buckets.into()),
+                ("bytes", memory.bytes.into()),
+            ])
+        }
+
+        /// Fires the events held back by the throttle whose interval has passed,
+        /// for callers that want them without waiting for the next update.
+        pub fn flush_events(&mut self) -> Result<(), JsValue> {
+            let events = self.watcher.flush(js_sys::Date::now());
+            self.dispatch(events)
+        }
+    }
+
+    impl BookHandle {
+        // Fires the events of an update, then returns its result. The error of
+        // the update is thrown rather than one thrown by a callback.
+        fn finish_update<T>(&mut self, result: Result<T, BookError>) -> Result<T, JsValue> {
+            let fired = self.fire_events();
+            let value = result?;
+            fired?;
+            Ok(value)
+        }
+
+        fn fire_events(&mut self) -> Result<(), JsValue> {
+            if self.callbacks.is_empty() {
+                return Ok(());
+            }
+            let events = self.watcher.observe(&mut self.book);
+            if events.is_empty() && self.watcher.interval <= 0.0 {
+                return Ok(());
+            }
+            let events = self.watcher.throttle(events, js_sys::Date::now());
+            self.dispatch(events)
+        }
+
+        fn dispatch(&mut self, events: Vec<BookEvent>) -> Result<(), JsValue> {
+            let calls = events
+                .into_iter()
+                .filter_map(|event| self.callbacks.get(&event.kind()).map(|callback| (callback.clone(), event)))
+                .collect::<Vec<(Function, BookEvent)>>();
+            if self.defer_callbacks {
+                self.deferred_calls.extend(calls);
+                Ok(())
+            } else {
+                call_callbacks(calls)
+            }
+        }
+
+        pub(crate) fn take_deferred_calls(&mut self) -> Vec<(Function, BookEvent)> {
+            std::mem::take(&mut self.deferred_calls)
+        }
+
+        pub(crate) fn book_memory(&self) -> BookMemory {
+            let mut memory = self.book.memory_usage();
+            memory.bytes += self.ladder.capacity() * std::mem::size_of::<f64>();
+            memory
+        }
+
+        pub(crate) fn write_ladder(&mut self, count: usize) -> &[f64] {
+            self.ladder.clear();
+            self.ladder.resize(LADDER_HEADER_LEN, 0.0);
+            let (asks, bids) = self.book.write_grouped_ladder(count, &mut self.ladder);
+            self.ladder[..LADDER_HEADER_LEN].copy_from_slice(&[
+                asks as f64,
+                bids as f64,
+                self.book.grouped_mid_price(),
+                self.book.grouping_strategy().group_size().unwrap_or(0.0),
+                self.book.sequence as f64,
+            ]);
+            &self.ladder
+        }
+
+        pub(crate) fn replace_top_levels(&mut self, snapshot: SnapshotMessage, depth: usize) -> Result<Vec<f64>, JsValue> {
+            let events = self.book.replace_top_levels_message(snapshot, depth);
+            let events = self.finish_update(events)?;
+            let mut out: Vec<f64> = Vec::with_capacity(events.len() * 4);
+            for event in events.iter() {
+                out.push(event.side as u8 as f64);
+                out.push(event.change as u8 as f64);
+                out.push(event.price.to_f64().unwrap_or_default());
+                out.push(event.size.to_f64().unwrap_or_default());
+            }
+            Ok(out)
+        }
+    }
+
+    impl From<OrderBook> for BookHandle {
+        fn from(book: OrderBook) -> BookHandle {
+            BookHandle {
+                book,
+                ladder: Vec::new(),
+                callbacks: BTreeMap::new(),
+                watcher: BookWatcher::default(),
+                defer_callbacks: false,
+                deferred_calls: Vec::new(),
+            }
+        }
+    }
+
+    fn flatten_levels(out: &mut Vec<f64>, asks: &[SnapshotLevel], bids: &[SnapshotLevel]) {
+        for level in asks.iter().rev() {
+            out.push(level.price);
+            out.push(level.total_size);
+        }
+        out.push(99999.99999);
+        out.push(99999.99999);
+        for level in bids.iter() {
+            out.push(level.price);
+            out.push(level.total_size);
+        }
+    }
+
+    fn flatten_grouped_snapshot(snapshot: &OrderBookSnapshot) -> Vec<f64> {
+        let mut out: Vec<f64> = Vec::new();
+        flatten_levels(&mut out, &snapshot.asks, &snapshot.bids);
+        out
+    }
+
+    fn flatten_grouped_changes(changes: &GroupedSnapshotChanges) -> Vec<f64> {
+        let mut out = vec![if changes.full { 1.0 } else { 0.0 }];
+        flatten_levels(&mut out, &changes.asks, &changes.bids);
+        out
+    }
+
+    /// Calls every callback even if one throws, then rethrows the first
+    /// exception.
+    pub(crate) fn call_callbacks(calls: Vec<(Function, BookEvent)>) -> Result<(), JsValue> {
+        let mut thrown = None;
+        for (callback, event) in calls {
+            let called = match event {
+                BookEvent::BestPrice { best_bid, best_ask } => callback.call2(&JsValue::NULL, &best_bid.into(), &best_ask.into()),
+                BookEvent::Spread { spread } => callback.call1(&JsValue::NULL, &spread.into()),
+                BookEvent::SequenceGap { sequence } | BookEvent::ResyncNeeded { sequence } => {
+                    callback.call1(&JsValue::NULL, &(sequence as f64).into())
+                }
+                BookEvent::LargeLevel { side, price, size } => {
+                    callback.call3(&JsValue::NULL, &(side as u8).into(), &price.into(), &size.into())
+                }
+            };
+            if let Err(error) = called {
+                thrown.get_or_insert(error);
+            }
+        }
+        match thrown {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn to_object(fields: &[(&str, JsValue)]) -> Result<Object, JsValue> {
+        let object = Object::new();
+        for (name, value) in fields.iter() {
+            Reflect::set(&object, &JsValue::from_str(name), value)?;
+        }
+        Ok(object)
+    }
+
+    fn to_grouping_strategy(strategy: u32, value: f64, reference: f64) -> Result<GroupingStrategy, JsValue> {
+        match strategy {
+            0 => Ok(GroupingStrategy::Absolute(value)),
+            1 => Ok(GroupingStrategy::BasisPoints { basis_points: value, reference }),
+            2 if (1.0..=u32::MAX as f64).contains(&value) => Ok(GroupingStrategy::Logarithmic { buckets_per_decade: value as u32 }),
+            2 => Err(BookError::InvalidSize(value.to_string()).into()),
+            _ => Err(BookError::InvalidArgument(format!("unknown grouping strategy {}", strategy)).into()),
+        }
+    }
+
+    fn to_event_kind(kind: u32) -> Result<BookEventKind, JsValue> {
+        match kind {
+            0 => Ok(BookEventKind::BestPrice),
+            1 => Ok(BookEventKind::Spread),
+            2 => Ok(BookEventKind::SequenceGap),
+            3 => Ok(BookEventKind::ResyncNeeded),
+            4 => Ok(BookEventKind::LargeLevel),
+            _ => Err(BookError::InvalidArgument(format!("unknown book event {}", kind)).into()),
+        }
+    }
+
+    fn to_nice_sequence(sequence: u32) -> Result<NiceSequence, JsValue> {
+        match sequence {
+            0 => Ok(NiceSequence::OneTwoFive),
+            1 => Ok(NiceSequence::OneTwoHalfFive),
+            _ => Err(BookError::InvalidArgument(format!("unknown group size sequence {}", sequence)).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::book_handle::{BookHandle, LADDER_HEADER_LEN};
+    use crate::book::book::OrderType;
+    use prost::Message;
+    use stock_messages::stock_messages::LevelUpdate;
+
+    #[test]
+    fn test_handle_owns_book() {
+        let bytes = std::fs::read("snapshots/Binance:BTC_USDT").unwrap();
+        let mut handle = BookHandle::from_snapshot(bytes).unwrap();
+        assert_eq!(handle.instrument(), "Binance:BTC/USDT");
+        handle.set_group_size(10.0).unwrap();
+
+        let snapshot = handle.grouped_snapshot(5);
+        assert_eq!(snapshot.len(), 22);
+        assert_eq!(snapshot[10], 99999.99999);
+
+        let ladder = handle.write_ladder(5).to_vec();
+        assert_eq!(ladder.len(), LADDER_HEADER_LEN + 20);
+        let filled = |sizes: &[f64]| sizes.iter().skip(1).step_by(2).filter(|size| **size != 0.0).count() as f64;
+        assert_eq!(ladder[0], filled(&ladder[LADDER_HEADER_LEN..LADDER_HEADER_LEN + 10]));
+        assert_eq!(ladder[1], filled(&ladder[LADDER_HEADER_LEN + 10..]));
+        assert_eq!(ladder[3], 10.0);
+        assert_eq!(ladder[4], handle.sequence());
+        let asks = snapshot[..10].chunks(2).rev().flatten();
+        let bids = snapshot[12..].iter();
+        assert!(asks.chain(bids).eq(ladder[LADDER_HEADER_LEN..].iter()));
+
+        let consumer = handle.register_snapshot_consumer(None).unwrap();
+        assert_eq!(handle.grouped_snapshot_changes(consumer, 5).unwrap()[0], 1.0);
+        let sequence = handle.book.sequence;
+        handle.book.add_level_str(OrderType::Bid, "9000", "1", sequence + 1).unwrap();
+        assert_eq!(handle.sequence(), (sequence + 1) as f64);
+        assert_eq!(handle.grouped_snapshot_changes(consumer, 5).unwrap()[0], 0.0);
+        assert!(handle.unregister_snapshot_consumer(consumer));
+    }
+
+    #[test]
+    fn test_one_sided_ladder_header() {
+        let mut handle = BookHandle::new("instrument");
+        handle.set_group_size(10.0).unwrap();
+        handle.book.add_level_str(OrderType::Bid, "100", "1", 1).unwrap();
+        handle.book.add_level_str(OrderType::Bid, "95", "2", 2).unwrap();
+
+        // the header mid is the one the buckets are centred on, not the best bid
+        let ladder = handle.write_ladder(3).to_vec();
+        assert_eq!(&ladder[..3], &[0.0, 0.0, 50.0]);
+        assert_eq!(ladder[LADDER_HEADER_LEN], 50.0);
+        assert_eq!(ladder[LADDER_HEADER_LEN + 6], 50.0);
+
+        handle.book.add_level_str(OrderType::Ask, "105", "1", 3).unwrap();
+        let ladder = handle.write_ladder(3).to_vec();
+        assert_eq!(&ladder[..3], &[1.0, 2.0, 105.0]);
+    }
+
+    #[test]
+    fn test_stale_update_is_reported() {
+        let mut handle = BookHandle::new("instrument");
+        let update = |sequence: i32| {
+            let message = LevelUpdate { r#type: 0, exchange: "".to_string(), price: 10.0, product_id: "".to_string(), sequence, side: 1, size: 1.0, time: 0, count: 0 };
+            let mut bytes = Vec::new();
+            message.encode(&mut bytes).unwrap();
+            bytes
+        };
+        assert_eq!(handle.update_level(update(1)), Ok(true));
+        assert_eq!(handle.update_level(update(1)), Ok(false));
+        assert_eq!(handle.sequence(), 1.0);
+    }
+}
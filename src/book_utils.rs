@@ -2,46 +2,139 @@ pub mod book {
     extern crate bigdecimal;
     use bigdecimal::BigDecimal;
     use bigdecimal::ToPrimitive;
-    use cached::proc_macro::cached;
+    use bigdecimal::num_bigint::BigInt;
     use std::ops::{Mul, Add, Sub, Div};
     use std::str::FromStr;
-    use std::cmp::max;
     use bigdecimal::RoundingMode;
-    use bigdecimal::FromPrimitive;
     use bigdecimal::Zero;
-    use cached::SizedCache;
 
+    /// Digits kept below the group size's scale when rounding away float noise
+    /// from prices before grouping them.
+    pub const GROUPING_GUARD_DIGITS: i64 = 4;
 
-    #[cached(
-        type = "SizedCache<String, i64>",
-        create = "{ SizedCache::with_size(1000000) }",
-        convert = r#"{ format!("{}", value) }"#
-    )]
+    // an f64 never needs more decimal places than this to print exactly
+    const MAX_F64_SCALE: i64 = 17;
+
+    /// Digits and scale of the decimal the value prints as, so 2.5 is (25, 1)
+    /// and 0.25 is (25, 2).
+    fn decimal_parts(value: f64) -> (i128, i64) {
+        let mut multiplier = 1f64;
+        for scale in 0..=MAX_F64_SCALE {
+            let digits = (value * multiplier).round();
+            if digits / multiplier == value {
+                return (digits as i128, scale);
+            }
+            multiplier *= 10.0;
+        }
+        ((value * 10f64.powi(MAX_F64_SCALE as i32)).round() as i128, MAX_F64_SCALE)
+    }
+
+    /// Number of decimal places of the value as printed, so 2.5 is 1 and 0.25
+    /// is 2 rather than the magnitude of the value.
     pub fn value_to_scale(value: f64) -> i64 {
-        let decimal_places = (-value.log10()).ceil() as i64;
-        max(0, decimal_places)
+        decimal_parts(value).1
+    }
+
+    /// The group size as the decimal it prints as, so 0.1 is exactly 0.1 and
+    /// 5e-8 is not rounded away.
+    pub fn group_size_decimal(group_size: f64) -> BigDecimal {
+        let (digits, scale) = decimal_parts(group_size);
+        BigDecimal::new(BigInt::from(digits), scale)
     }
 
-    #[cached(
-        type = "SizedCache<String, BigDecimal>",
-        create = "{ SizedCache::with_size(1000000) }",
-        convert = r#"{ format!("{}{}{}", decimal, group_size, group_lower) }"#
-    )]
+    /// The multiple of `group_size` at or below the price for bids
+    /// (`group_lower`), at or above it for asks. Works on the integer digits
+    /// of both so no division at arbitrary precision is needed.
     pub fn group(decimal: BigDecimal, group_size: f64, group_lower: bool) -> BigDecimal {
-        let scale = value_to_scale(group_size);
-        let group_decimal = BigDecimal::from_f64(group_size).unwrap().with_scale_round(4, RoundingMode::HalfUp);
-        let decimal = decimal.with_scale_round(4, RoundingMode::HalfUp); // this is to round the decimal imperfections like 100.30000001, 100.299999 for 100.3
-        let rounding_mode: RoundingMode = if group_lower { RoundingMode::Floor } else { RoundingMode::Ceiling };
-        let div = (decimal.clone() / group_decimal.clone()).with_scale_round(0, RoundingMode::Floor);
-        // println!("{} / {} = {}", decimal, group_decimal, div);
-        let calculated = (div * group_decimal.clone()).with_scale_round(scale, RoundingMode::Floor);
-        if calculated == decimal {
-            // println!("{} == {}", calculated, decimal);
-            return calculated;
+        let (group_digits, scale) = decimal_parts(group_size);
+        if group_digits <= 0 {
+            return decimal;
+        }
+        // this is to round the decimal imperfections like 100.30000001, 100.299999 for 100.3
+        let (price, _) = decimal
+            .with_scale_round(scale + GROUPING_GUARD_DIGITS, RoundingMode::HalfUp)
+            .into_bigint_and_exponent();
+        let group = BigInt::from(group_digits * 10i128.pow(GROUPING_GUARD_DIGITS as u32));
+        let mut bucket = &price / &group;
+        let remainder = price - &bucket * &group;
+        if group_lower && remainder < BigInt::zero() {
+            bucket -= 1;
+        } else if !group_lower && remainder > BigInt::zero() {
+            bucket += 1;
+        }
+        BigDecimal::new(bucket * group_digits, scale)
+    }
+
+    /// Formats a decimal without exponent notation, keeping its scale, so
+    /// "0.00000210" comes back out exactly as it went in.
+    pub fn to_plain_string(decimal: &BigDecimal) -> String {
+        let (digits, scale) = decimal.as_bigint_and_exponent();
+        let mut plain = digits.magnitude().to_str_radix(10);
+        if scale <= 0 {
+            plain.push_str(&"0".repeat(-scale as usize));
+        } else {
+            let scale = scale as usize;
+            if plain.len() <= scale {
+                plain = "0".repeat(scale - plain.len() + 1) + &plain;
+            }
+            plain.insert(plain.len() - scale, '.');
+        }
+        if decimal < &BigDecimal::zero() {
+            plain.insert(0, '-');
+        }
+        plain
+    }
+
+    #[test]
+    fn test_plain_string() {
+        let cases = vec!["0.00000210", "9015.85", "-0.5", "1200", "0"];
+        for case in &cases {
+            assert_eq!(to_plain_string(&BigDecimal::from_str(case).unwrap()), case.to_string());
+        }
+        assert_eq!(to_plain_string(&BigDecimal::new(12.into(), -2)), "1200");
+    }
+
+    #[test]
+    fn test_satoshi_group_sizes() {
+        let cases = vec![
+            //value, grouping, bid, ask
+            ("0.00000213", 1e-8, "0.00000213", "0.00000213"),
+            ("0.00000213", 5e-8, "0.0000021", "0.00000215"),
+            ("0.00000213", 1e-7, "0.0000021", "0.0000022"),
+            ("0.000002130000000001", 1e-8, "0.00000213", "0.00000213"),
+            ("0.00005", 0.00005, "0.00005", "0.00005"),
+        ];
+        for case in &cases {
+            let value = BigDecimal::from_str(case.0).unwrap();
+            assert_eq!(group(value.clone(), case.1, true), BigDecimal::from_str(case.2).unwrap(), "bid {} by {}", case.0, case.1);
+            assert_eq!(group(value, case.1, false), BigDecimal::from_str(case.3).unwrap(), "ask {} by {}", case.0, case.1);
+        }
+        assert_eq!(group_size_decimal(5e-8), BigDecimal::from_str("0.00000005").unwrap());
+    }
+
+    #[test]
+    fn test_non_power_of_ten_group_sizes() {
+        let cases = vec![
+            //value, grouping, bid, ask
+            ("102.7", 2.5, "102.5", "105"),
+            ("102.5", 2.5, "102.5", "102.5"),
+            ("0.26", 0.25, "0.25", "0.5"),
+            ("1234", 25.0, "1225", "1250"),
+            ("1234", 250.0, "1000", "1250"),
+            ("9015.85", 0.25, "9015.75", "9016"),
+        ];
+        for case in &cases {
+            let value = BigDecimal::from_str(case.0).unwrap();
+            assert_eq!(group(value.clone(), case.1, true), BigDecimal::from_str(case.2).unwrap(), "bid {} by {}", case.0, case.1);
+            assert_eq!(group(value, case.1, false), BigDecimal::from_str(case.3).unwrap(), "ask {} by {}", case.0, case.1);
         }
-        // println!("{} != {}", calculated, decimal);
-        let rounded_value = (calculated + if group_lower { BigDecimal::zero() } else { group_decimal }).with_scale_round(scale, rounding_mode);
-        return rounded_value;
+        assert_eq!(value_to_scale(2.5), 1);
+        assert_eq!(value_to_scale(0.25), 2);
+        assert_eq!(value_to_scale(250.0), 0);
+        assert_eq!(value_to_scale(1e-8), 8);
+        // values needing more than 17 places are rounded to 17
+        assert_eq!(value_to_scale(1.23e-16), 17);
+        assert_eq!(group_size_decimal(1.23e-16), BigDecimal::from_str("0.00000000000000012").unwrap());
     }
 
     #[test]
@@ -0,0 +1,179 @@
+extern crate crc32fast;
+
+pub mod checksum {
+    use crate::book::book::{Level, OrderBook};
+    use crate::book_utils::book::to_plain_string;
+    use bigdecimal::BigDecimal;
+    use num_traits::identities::Zero;
+    use serde::{Deserialize, Serialize};
+
+    /// CRC32 checksums published by venues over the top of their books. Each
+    /// variant formats prices and sizes the way its venue does before hashing.
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+    pub enum ChecksumAlgorithm {
+        /// Top 25 levels as `bid:size:ask:size:...`, prices and sizes exactly
+        /// as sent by the exchange.
+        Okx = 0,
+        /// Top 10 asks then top 10 bids, each price and size with the decimal
+        /// point and leading zeros removed, concatenated.
+        Kraken = 1,
+        /// Top 25 levels as `bid:amount:ask:-amount:...`, numbers formatted as
+        /// JavaScript prints them.
+        Bitfinex = 2,
+    }
+
+    impl ChecksumAlgorithm {
+        pub fn depth(&self) -> usize {
+            match self {
+                ChecksumAlgorithm::Okx => 25,
+                ChecksumAlgorithm::Kraken => 10,
+                ChecksumAlgorithm::Bitfinex => 25,
+            }
+        }
+
+        /// The string the venue hashes for this book.
+        pub fn checksum_string(&self, book: &OrderBook) -> String {
+            let depth = self.depth();
+            let bids = book.bids.values().rev().take(depth).collect::<Vec<&Level>>();
+            let asks = book.asks.values().take(depth).collect::<Vec<&Level>>();
+            match self {
+                ChecksumAlgorithm::Okx => interleave(&bids, &asks, |decimal, _| to_plain_string(decimal)),
+                ChecksumAlgorithm::Bitfinex => interleave(&bids, &asks, |decimal, negate| {
+                    let plain = js_number_string(decimal);
+                    if negate {
+                        format!("-{}", plain)
+                    } else {
+                        plain
+                    }
+                }),
+                ChecksumAlgorithm::Kraken => asks
+                    .iter()
+                    .chain(bids.iter())
+                    .map(|level| format!("{}{}", kraken_digits(&level.price), kraken_digits(&level.size)))
+                    .collect(),
+            }
+        }
+
+        pub fn compute(&self, book: &OrderBook) -> u32 {
+            crc32fast::hash(self.checksum_string(book).as_bytes())
+        }
+    }
+
+    // `format` gets the decimal and whether it is an ask size.
+    fn interleave<F>(bids: &[&Level], asks: &[&Level], format: F) -> String
+    where
+        F: Fn(&BigDecimal, bool) -> String,
+    {
+        let mut parts = Vec::with_capacity(4 * bids.len().max(asks.len()));
+        for index in 0..bids.len().max(asks.len()) {
+            if let Some(bid) = bids.get(index) {
+                parts.push(format(&bid.price, false));
+                parts.push(format(&bid.size, false));
+            }
+            if let Some(ask) = asks.get(index) {
+                parts.push(format(&ask.price, false));
+                parts.push(format(&ask.size, true));
+            }
+        }
+        parts.join(":")
+    }
+
+    fn kraken_digits(decimal: &BigDecimal) -> String {
+        let digits = to_plain_string(decimal).replace('.', "");
+        let trimmed = digits.trim_start_matches('0');
+        if trimmed.is_empty() {
+            "0".to_string()
+        } else {
+            trimmed.to_string()
+        }
+    }
+
+    /// Formats like JavaScript's `Number.prototype.toString`: shortest form,
+    /// switching to exponent notation below 1e-6 and from 1e21.
+    pub fn js_number_string(decimal: &BigDecimal) -> String {
+        let normalized = decimal.normalized();
+        let (digits, scale) = normalized.as_bigint_and_exponent();
+        let magnitude = digits.magnitude().to_str_radix(10);
+        if magnitude == "0" {
+            return "0".to_string();
+        }
+        let exponent = magnitude.len() as i64 - 1 - scale;
+        if (-6..21).contains(&exponent) {
+            return to_plain_string(&normalized);
+        }
+        let sign = if decimal < &BigDecimal::zero() { "-" } else { "" };
+        let mantissa = if magnitude.len() > 1 {
+            format!("{}.{}", &magnitude[..1], &magnitude[1..])
+        } else {
+            magnitude
+        };
+        format!("{}{}e{}{}", sign, mantissa, if exponent < 0 { "-" } else { "+" }, exponent.abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::checksum::{js_number_string, ChecksumAlgorithm};
+    use crate::book::book::{OrderBook, OrderType, SyncState};
+    use crate::error::error::BookError;
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+
+    fn create_book(bids: Vec<(&str, &str)>, asks: Vec<(&str, &str)>) -> OrderBook {
+        let mut book = OrderBook::new("instrument", 0);
+        for (price, size) in bids {
+            book.add_level_str(OrderType::Bid, price, size, 0).unwrap();
+        }
+        for (price, size) in asks {
+            book.add_level_str(OrderType::Ask, price, size, 0).unwrap();
+        }
+        book
+    }
+
+    #[test]
+    fn test_okx_checksum() {
+        let book = create_book(vec![("3366.1", "7"), ("3366", "6")], vec![("3366.8", "9"), ("3368", "8")]);
+        let algorithm = ChecksumAlgorithm::Okx;
+        assert_eq!(algorithm.checksum_string(&book), "3366.1:7:3366.8:9:3366:6:3368:8");
+        assert_eq!(algorithm.compute(&book) as i32, -1881014294);
+    }
+
+    #[test]
+    fn test_checksum_mismatch_requires_snapshot() {
+        let mut book = create_book(vec![("3366.1", "7"), ("3366", "6")], vec![("3366.8", "9"), ("3368", "8")]);
+        assert_eq!(book.verify_checksum(42), Ok(()));
+        book.set_checksum_algorithm(Some(ChecksumAlgorithm::Okx));
+        assert_eq!(book.verify_checksum(-1881014294), Ok(()));
+        book.add_level_str(OrderType::Ask, "3368", "7", 1).unwrap();
+        assert!(matches!(book.verify_checksum(-1881014294), Err(BookError::ChecksumMismatch { .. })));
+        assert_eq!(book.sync_state(), SyncState::AwaitingSnapshot);
+    }
+
+    #[test]
+    fn test_kraken_checksum() {
+        let book = create_book(
+            vec![("0.05005", "0.00000500"), ("0.05004", "1.50000000")],
+            vec![("0.05006", "0.10000000")],
+        );
+        let algorithm = ChecksumAlgorithm::Kraken;
+        assert_eq!(algorithm.checksum_string(&book), "50061000000050055005004150000000");
+        assert_eq!(algorithm.compute(&book), 1582581791);
+
+        // the example book of Kraken's checksum guide
+        let asks = ["0.05005", "0.05010", "0.05015", "0.05020", "0.05025", "0.05030", "0.05035", "0.05040", "0.05045", "0.05050"];
+        let bids = ["0.05000", "0.04995", "0.04990", "0.04980", "0.04975", "0.04970", "0.04965", "0.04960", "0.04955", "0.04950"];
+        let levels = |prices: &[&'static str]| prices.iter().map(|price| (*price, "0.00000500")).collect::<Vec<(&str, &str)>>();
+        let book = create_book(levels(&bids), levels(&asks));
+        assert_eq!(algorithm.compute(&book), 974947235);
+    }
+
+    #[test]
+    fn test_bitfinex_checksum() {
+        let book = create_book(vec![("6000.0", "1.50"), ("5999", "0.00000001")], vec![("6001", "2")]);
+        let algorithm = ChecksumAlgorithm::Bitfinex;
+        assert_eq!(algorithm.checksum_string(&book), "6000:1.5:6001:-2:5999:1e-8");
+        assert_eq!(algorithm.compute(&book) as i32, 1733611670);
+        assert_eq!(js_number_string(&BigDecimal::from_str("0.000001").unwrap()), "0.000001");
+        assert_eq!(js_number_string(&BigDecimal::from_str("12e20").unwrap()), "1.2e+21");
+    }
+}
@@ -0,0 +1,67 @@
+pub mod consumer {
+    use crate::book::book::{OrderType, Price, SnapshotLevel};
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeSet;
+
+    /// Changed buckets a consumer may pile up before it is simply sent the
+    /// full ladder again.
+    pub const MAX_DIRTY_BUCKETS: usize = 4096;
+
+    /// What one reader of grouped snapshots has been sent so far, and which
+    /// buckets changed since.
+    #[derive(Debug, Clone)]
+    pub struct SnapshotConsumer {
+        /// Grouping view read by the consumer, None for the default grouping.
+        pub view: Option<String>,
+        pub count: usize,
+        /// Top bid and ask buckets of the last ladder sent.
+        pub anchor: Option<(Price, Price)>,
+        pub dirty_bids: BTreeSet<Price>,
+        pub dirty_asks: BTreeSet<Price>,
+        pub needs_full: bool,
+    }
+
+    impl SnapshotConsumer {
+        pub fn new(view: Option<String>) -> SnapshotConsumer {
+            SnapshotConsumer {
+                view,
+                count: 0,
+                anchor: None,
+                dirty_bids: BTreeSet::new(),
+                dirty_asks: BTreeSet::new(),
+                needs_full: true,
+            }
+        }
+
+        pub fn mark_dirty(&mut self, order_type: OrderType, bucket: Price) {
+            if self.needs_full {
+                return;
+            }
+            match order_type {
+                OrderType::Bid => self.dirty_bids.insert(bucket),
+                OrderType::Ask => self.dirty_asks.insert(bucket),
+            };
+            if self.dirty_bids.len() + self.dirty_asks.len() > MAX_DIRTY_BUCKETS {
+                self.invalidate();
+            }
+        }
+
+        /// The next snapshot sent to the consumer is the full ladder.
+        pub fn invalidate(&mut self) {
+            self.needs_full = true;
+            self.dirty_bids.clear();
+            self.dirty_asks.clear();
+        }
+    }
+
+    /// Grouped levels changed since the consumer's previous call, laid out like
+    /// `OrderBookSnapshot`. When `full` is set the whole ladder is included,
+    /// because it is the first call, the count or grouping changed, or the mid
+    /// bucket moved.
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct GroupedSnapshotChanges {
+        pub full: bool,
+        pub bids: Vec<SnapshotLevel>,
+        pub asks: Vec<SnapshotLevel>,
+    }
+}
@@ -0,0 +1,114 @@
+pub mod error {
+    use std::fmt;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum BookError {
+        /// The protobuf frame could not be decoded.
+        Decode(String),
+        /// `side` is not a known `Side` value.
+        UnknownSide(i32),
+        /// The update is older than the book and was ignored.
+        StaleSequence { expected: u64, received: u64 },
+        /// The update is ahead of the book; the book is no longer in sync.
+        SequenceGap { expected: u64, received: u64 },
+        InvalidPrice(String),
+        InvalidSize(String),
+        CrossedBook { best_bid: f64, best_ask: f64 },
+        UnknownBook(u32),
+        UnknownOrder(String),
+        DuplicateOrder(String),
+        /// A delta update would leave the level at `price` with a negative size.
+        NegativeLevel { price: String, size: String },
+        /// The venue checksum does not match the one computed over the book.
+        ChecksumMismatch { expected: u32, computed: u32 },
+        /// No grouping view with this name was added to the book.
+        UnknownView(String),
+        /// No snapshot consumer with this id is registered on the book.
+        UnknownConsumer(u32),
+        /// An enum index or setting passed through the wasm exports is out of range.
+        InvalidArgument(String),
+    }
+
+    impl BookError {
+        /// Stable numeric code for callers that cannot match on the enum, such
+        /// as the wasm exports.
+        pub fn code(&self) -> u32 {
+            match self {
+                BookError::Decode(_) => 1,
+                BookError::UnknownSide(_) => 2,
+                BookError::StaleSequence { .. } => 3,
+                BookError::SequenceGap { .. } => 4,
+                BookError::InvalidPrice(_) => 5,
+                BookError::InvalidSize(_) => 6,
+                BookError::CrossedBook { .. } => 7,
+                BookError::UnknownBook(_) => 8,
+                BookError::UnknownOrder(_) => 9,
+                BookError::DuplicateOrder(_) => 10,
+                BookError::NegativeLevel { .. } => 11,
+                BookError::ChecksumMismatch { .. } => 12,
+                BookError::UnknownView(_) => 13,
+                BookError::UnknownConsumer(_) => 14,
+                BookError::InvalidArgument(_) => 15,
+            }
+        }
+
+        /// Name of the variant, for callers matching on errors by name.
+        pub fn name(&self) -> &'static str {
+            match self {
+                BookError::Decode(_) => "Decode",
+                BookError::UnknownSide(_) => "UnknownSide",
+                BookError::StaleSequence { .. } => "StaleSequence",
+                BookError::SequenceGap { .. } => "SequenceGap",
+                BookError::InvalidPrice(_) => "InvalidPrice",
+                BookError::InvalidSize(_) => "InvalidSize",
+                BookError::CrossedBook { .. } => "CrossedBook",
+                BookError::UnknownBook(_) => "UnknownBook",
+                BookError::UnknownOrder(_) => "UnknownOrder",
+                BookError::DuplicateOrder(_) => "DuplicateOrder",
+                BookError::NegativeLevel { .. } => "NegativeLevel",
+                BookError::ChecksumMismatch { .. } => "ChecksumMismatch",
+                BookError::UnknownView(_) => "UnknownView",
+                BookError::UnknownConsumer(_) => "UnknownConsumer",
+                BookError::InvalidArgument(_) => "InvalidArgument",
+            }
+        }
+    }
+
+    impl fmt::Display for BookError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                BookError::Decode(reason) => write!(f, "failed to decode message: {}", reason),
+                BookError::UnknownSide(side) => write!(f, "unknown side {}", side),
+                BookError::StaleSequence { expected, received } => {
+                    write!(f, "stale sequence {}, expected {}", received, expected)
+                }
+                BookError::SequenceGap { expected, received } => {
+                    write!(f, "sequence gap, received {} expected {}", received, expected)
+                }
+                BookError::InvalidPrice(price) => write!(f, "invalid price {}", price),
+                BookError::InvalidSize(size) => write!(f, "invalid size {}", size),
+                BookError::CrossedBook { best_bid, best_ask } => {
+                    write!(f, "crossed book, best bid {} best ask {}", best_bid, best_ask)
+                }
+                BookError::UnknownBook(book_id) => write!(f, "unknown book {}", book_id),
+                BookError::UnknownOrder(order_id) => write!(f, "unknown order {}", order_id),
+                BookError::DuplicateOrder(order_id) => write!(f, "duplicate order {}", order_id),
+                BookError::NegativeLevel { price, size } => write!(f, "level {} would have negative size {}", price, size),
+                BookError::ChecksumMismatch { expected, computed } => {
+                    write!(f, "checksum mismatch, expected {} computed {}", expected, computed)
+                }
+                BookError::UnknownView(name) => write!(f, "unknown grouping view {}", name),
+                BookError::UnknownConsumer(consumer) => write!(f, "unknown snapshot consumer {}", consumer),
+                BookError::InvalidArgument(reason) => write!(f, "{}", reason),
+            }
+        }
+    }
+
+    impl std::error::Error for BookError {}
+
+    impl From<prost::DecodeError> for BookError {
+        fn from(error: prost::DecodeError) -> Self {
+            BookError::Decode(error.to_string())
+        }
+    }
+}
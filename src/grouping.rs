@@ -0,0 +1,408 @@
+pub mod grouping {
+    use crate::book::book::{Level, OrderBook, OrderType, Price, Size};
+    use crate::book_utils::book::{group, group_size_decimal, value_to_scale};
+    use crate::error::error::BookError;
+    use bigdecimal::{BigDecimal, FromPrimitive, RoundingMode, ToPrimitive};
+    use num_traits::identities::Zero;
+    use serde::{Deserialize, Serialize};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    // significant digits kept for logarithmic bucket prices
+    const LOG_BUCKET_DIGITS: i64 = 6;
+
+    /// How prices are assigned to groups. Bids go to the bucket at or below
+    /// the price and asks to the bucket at or above it.
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+    pub enum GroupingStrategy {
+        /// Fixed width buckets at multiples of the group size.
+        Absolute(f64),
+        /// Buckets `basis_points` of the reference price wide, anchored at it.
+        BasisPoints { basis_points: f64, reference: f64 },
+        /// Buckets at `10^(k / buckets_per_decade)`, the same number per decade
+        /// at any price.
+        Logarithmic { buckets_per_decade: u32 },
+    }
+
+    impl Default for GroupingStrategy {
+        fn default() -> Self {
+            GroupingStrategy::Absolute(1.0)
+        }
+    }
+
+    impl GroupingStrategy {
+        pub fn validate(&self) -> Result<(), BookError> {
+            let valid = |value: f64| value.is_finite() && value > 0.0;
+            match *self {
+                GroupingStrategy::Absolute(group_size) if !valid(group_size) => Err(BookError::InvalidSize(group_size.to_string())),
+                GroupingStrategy::BasisPoints { basis_points, .. } if !valid(basis_points) => {
+                    Err(BookError::InvalidSize(basis_points.to_string()))
+                }
+                GroupingStrategy::BasisPoints { reference, .. } if !valid(reference) => Err(BookError::InvalidPrice(reference.to_string())),
+                GroupingStrategy::Logarithmic { buckets_per_decade: 0 } => Err(BookError::InvalidSize("0".to_string())),
+                _ => Ok(()),
+            }
+        }
+
+        /// Width of a bucket, None for logarithmic buckets.
+        pub fn group_size(&self) -> Option<f64> {
+            match *self {
+                GroupingStrategy::Absolute(group_size) => Some(group_size),
+                GroupingStrategy::BasisPoints { .. } => self.bucket_width().to_f64(),
+                GroupingStrategy::Logarithmic { .. } => None,
+            }
+        }
+
+        fn bucket_width(&self) -> BigDecimal {
+            match *self {
+                GroupingStrategy::Absolute(group_size) => group_size_decimal(group_size),
+                GroupingStrategy::BasisPoints { basis_points, reference } => {
+                    let reference = BigDecimal::from_f64(reference).unwrap_or_default();
+                    let basis_points = BigDecimal::from_f64(basis_points).unwrap_or_default();
+                    (reference * basis_points / BigDecimal::from(10000)).normalized()
+                }
+                GroupingStrategy::Logarithmic { .. } => BigDecimal::zero(),
+            }
+        }
+
+        /// The bucket `price` falls in.
+        pub fn bucket(&self, price: &Price, group_lower: bool) -> Price {
+            let rounding_mode = if group_lower { RoundingMode::Floor } else { RoundingMode::Ceiling };
+            match *self {
+                GroupingStrategy::Absolute(group_size) => group(price.clone(), group_size, group_lower),
+                GroupingStrategy::BasisPoints { reference, .. } => {
+                    let reference = BigDecimal::from_f64(reference).unwrap_or_default();
+                    let width = self.bucket_width();
+                    // round away float noise like 100.2999999 before picking the bucket
+                    let index = ((price - &reference) / &width)
+                        .with_scale_round(8, RoundingMode::HalfUp)
+                        .with_scale_round(0, rounding_mode);
+                    reference + index * width
+                }
+                GroupingStrategy::Logarithmic { buckets_per_decade } => {
+                    let price = match price.to_f64() {
+                        Some(price) if price > 0.0 => price,
+                        _ => return BigDecimal::zero(),
+                    };
+                    let position = price.log10() * buckets_per_decade as f64;
+                    let index = if group_lower { (position + 1e-9).floor() } else { (position - 1e-9).ceil() };
+                    log_bucket(index as i64, buckets_per_decade)
+                }
+            }
+        }
+
+        /// The bucket next to `bucket`, above it if `up`.
+        pub fn next_bucket(&self, bucket: &Price, up: bool) -> Price {
+            match *self {
+                GroupingStrategy::Absolute(group_size) => {
+                    let width = self.bucket_width();
+                    let next = if up { bucket + width } else { bucket - width };
+                    next.with_scale_round(value_to_scale(group_size), RoundingMode::HalfUp)
+                }
+                GroupingStrategy::BasisPoints { .. } => {
+                    let width = self.bucket_width();
+                    if up {
+                        bucket + width
+                    } else {
+                        bucket - width
+                    }
+                }
+                GroupingStrategy::Logarithmic { buckets_per_decade } => {
+                    let bucket = match bucket.to_f64() {
+                        Some(bucket) if bucket > 0.0 => bucket,
+                        _ => return BigDecimal::zero(),
+                    };
+                    let index = (bucket.log10() * buckets_per_decade as f64).round() as i64;
+                    log_bucket(if up { index + 1 } else { index - 1 }, buckets_per_decade)
+                }
+            }
+        }
+    }
+
+    fn log_bucket(index: i64, buckets_per_decade: u32) -> Price {
+        let exponent = index as f64 / buckets_per_decade as f64;
+        let price = 10f64.powf(exponent);
+        let scale = LOG_BUCKET_DIGITS - 1 - exponent.floor() as i64;
+        BigDecimal::from_f64(price)
+            .unwrap_or_default()
+            .with_scale_round(scale, RoundingMode::HalfUp)
+            .normalized()
+    }
+
+    /// Mantissas the suggested group sizes step through in each decade.
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+    pub enum NiceSequence {
+        /// 1, 2, 5, 10, 20, 50, ...
+        OneTwoFive = 0,
+        /// 1, 2.5, 5, 10, 25, 50, ...
+        OneTwoHalfFive = 1,
+    }
+
+    impl NiceSequence {
+        // (digits, scale) of each mantissa
+        fn mantissas(&self) -> &'static [(i64, i64)] {
+            match self {
+                NiceSequence::OneTwoFive => &[(1, 0), (2, 0), (5, 0)],
+                NiceSequence::OneTwoHalfFive => &[(1, 0), (25, 1), (5, 0)],
+            }
+        }
+    }
+
+    /// Group sizes of the sequence between `min` and `max` inclusive, smallest first.
+    pub fn nice_group_sizes(min: f64, max: f64, sequence: NiceSequence) -> Vec<f64> {
+        if !(min.is_finite() && max.is_finite() && min > 0.0 && min <= max) {
+            return Vec::new();
+        }
+        let min_decimal = group_size_decimal(min);
+        let max_decimal = group_size_decimal(max);
+        let mut sizes = Vec::new();
+        for exponent in (min.log10().floor() as i64 - 1)..=(max.log10().ceil() as i64) {
+            for (digits, scale) in sequence.mantissas() {
+                let size = BigDecimal::new((*digits).into(), scale - exponent);
+                if size >= min_decimal && size <= max_decimal {
+                    sizes.push(size.to_f64().unwrap_or_default());
+                }
+            }
+        }
+        sizes
+    }
+
+    impl OrderBook {
+        /// Mid of the best bid and ask, or the best price of the only side
+        /// with levels.
+        pub fn mid_price(&self) -> Option<f64> {
+            match (self.bids.is_empty(), self.asks.is_empty()) {
+                (false, false) => Some((self.get_best_bid() + self.get_best_ask()) / 2.0),
+                (false, true) => Some(self.get_best_bid()),
+                (true, false) => Some(self.get_best_ask()),
+                (true, true) => None,
+            }
+        }
+
+        /// Number of non-empty groups on both sides within `percent` of mid.
+        pub fn count_groups(&self, group_size: f64, percent: f64) -> usize {
+            let mid = match self.mid_price() {
+                Some(mid) => mid,
+                None => return 0,
+            };
+            let strategy = GroupingStrategy::Absolute(group_size);
+            let lowest_bid = BigDecimal::from_f64(mid * (1.0 - percent / 100.0)).unwrap_or_default();
+            let highest_ask = BigDecimal::from_f64(mid * (1.0 + percent / 100.0)).unwrap_or_default();
+            let bid_groups = self
+                .bids
+                .range(lowest_bid..)
+                .map(|(price, _)| strategy.bucket(price, true))
+                .collect::<BTreeSet<Price>>();
+            let ask_groups = self
+                .asks
+                .range(..=highest_ask)
+                .map(|(price, _)| strategy.bucket(price, false))
+                .collect::<BTreeSet<Price>>();
+            bid_groups.len() + ask_groups.len()
+        }
+
+        /// Sensible group sizes for the book, from its tick size up to
+        /// `max_percent` of mid. Sizes that are not a multiple of the tick, or
+        /// that group the levels within `max_percent` of mid into as many
+        /// groups as the next smaller size, are left out.
+        pub fn suggest_group_sizes(&self, max_percent: f64, sequence: NiceSequence) -> Vec<f64> {
+            let (mid, tick_size) = match (self.mid_price(), self.tick_size()) {
+                (Some(mid), Some(tick_size)) => (mid, tick_size),
+                _ => return Vec::new(),
+            };
+            let tick_decimal = group_size_decimal(tick_size);
+            let mut suggestions: Vec<f64> = Vec::new();
+            let mut previous_count = None;
+            for group_size in nice_group_sizes(tick_size, mid * max_percent / 100.0, sequence) {
+                if !(group_size_decimal(group_size) % &tick_decimal).is_zero() {
+                    continue;
+                }
+                let count = self.count_groups(group_size, max_percent);
+                if previous_count == Some(count) {
+                    continue;
+                }
+                previous_count = Some(count);
+                suggestions.push(group_size);
+            }
+            suggestions
+        }
+
+        /// The suggested group size giving closest to `buckets` non-empty
+        /// groups within `percent` of mid, the smaller one on a tie.
+        pub fn group_size_for_buckets(&self, buckets: usize, percent: f64, sequence: NiceSequence) -> Option<f64> {
+            self.suggest_group_sizes(percent, sequence)
+                .into_iter()
+                .min_by_key(|group_size| (self.count_groups(*group_size, percent) as i64 - buckets as i64).abs())
+        }
+    }
+
+    /// A grouping of the book with its own strategy, kept up to date by every
+    /// level change alongside the book's default grouping.
+    #[derive(Debug, Clone)]
+    pub struct GroupingView {
+        pub strategy: GroupingStrategy,
+        pub grouped_bids: BTreeMap<Price, Size>,
+        pub grouped_asks: BTreeMap<Price, Size>,
+    }
+
+    impl GroupingView {
+        pub fn new(strategy: GroupingStrategy, bids: &BTreeMap<Price, Level>, asks: &BTreeMap<Price, Level>) -> GroupingView {
+            let mut view = GroupingView {
+                strategy,
+                grouped_bids: BTreeMap::new(),
+                grouped_asks: BTreeMap::new(),
+            };
+            view.rebuild(bids, asks);
+            view
+        }
+
+        pub fn rebuild(&mut self, bids: &BTreeMap<Price, Level>, asks: &BTreeMap<Price, Level>) {
+            self.grouped_bids = group_levels(bids, &self.strategy, true);
+            self.grouped_asks = group_levels(asks, &self.strategy, false);
+        }
+
+        /// Moves the group holding `price` from `previous_size` to `size`.
+        pub fn apply(&mut self, order_type: OrderType, price: &Price, previous_size: &Size, size: &Size) {
+            let (grouped, group_lower) = match order_type {
+                OrderType::Bid => (&mut self.grouped_bids, true),
+                OrderType::Ask => (&mut self.grouped_asks, false),
+            };
+            let group_price = self.strategy.bucket(price, group_lower);
+            let group_size = grouped.entry(group_price.clone()).or_insert_with(BigDecimal::zero);
+            *group_size -= previous_size;
+            *group_size += size;
+            if group_size.is_zero() {
+                grouped.remove(&group_price);
+            }
+        }
+    }
+
+    pub fn group_levels(levels: &BTreeMap<Price, Level>, strategy: &GroupingStrategy, group_lower: bool) -> BTreeMap<Price, Size> {
+        let mut grouped: BTreeMap<Price, Size> = BTreeMap::new();
+        for (price, level) in levels.iter() {
+            *grouped.entry(strategy.bucket(price, group_lower)).or_default() += &level.size;
+        }
+        grouped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::grouping::{nice_group_sizes, GroupingStrategy, NiceSequence};
+    use crate::book::book::{OrderBook, OrderType};
+    use crate::error::error::BookError;
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+    use prost::Message;
+    use stock_messages::stock_messages::SnapshotMessage;
+
+    #[test]
+    fn test_views_follow_updates() {
+        let bytes = std::fs::read("snapshots/Binance:BTC_USDT").unwrap();
+        let mut book = OrderBook::from(SnapshotMessage::decode(bytes).unwrap());
+        book.add_grouping_view("fine", GroupingStrategy::Absolute(0.5)).unwrap();
+        book.add_grouping_view("coarse", GroupingStrategy::Absolute(10.0)).unwrap();
+        let sequence = book.sequence;
+        book.add_level_str(OrderType::Bid, "9015.85", "1.5", sequence + 1).unwrap();
+        book.add_level_str(OrderType::Ask, "9020", "2", sequence + 2).unwrap();
+        book.remove_level_str(OrderType::Ask, "9017.78", sequence + 3).unwrap();
+        assert!(book.audit().is_clean());
+
+        for (name, group_size) in [("fine", 0.5), ("coarse", 10.0)] {
+            let mut expected = book.clone();
+            expected.set_group_size(group_size);
+            let view = book.grouping_view(name).unwrap();
+            assert_eq!(view.grouped_bids, expected.grouped_bids);
+            assert_eq!(view.grouped_asks, expected.grouped_asks);
+            let snapshot = book.get_view_grouped_snapshot(name, 5).unwrap();
+            assert_eq!(snapshot.bids.len(), 5);
+            assert_eq!(snapshot.bids[0].total_size, expected.get_grouped_snapshot_new(5).bids[0].total_size);
+        }
+        // the default grouping is untouched
+        assert_eq!(book.get_group_size(), 1.0);
+
+        assert!(book.remove_grouping_view("fine"));
+        assert!(!book.remove_grouping_view("fine"));
+        assert!(matches!(book.get_view_grouped_snapshot("fine", 5), Err(BookError::UnknownView(_))));
+        assert!(matches!(book.add_grouping_view("zero", GroupingStrategy::Absolute(0.0)), Err(BookError::InvalidSize(_))));
+    }
+
+    fn decimal(value: &str) -> BigDecimal {
+        BigDecimal::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn test_basis_point_buckets() {
+        let strategy = GroupingStrategy::BasisPoints {
+            basis_points: 10.0,
+            reference: 9000.0,
+        };
+        assert_eq!(strategy.group_size(), Some(9.0));
+        assert_eq!(strategy.bucket(&decimal("9015.85"), true), decimal("9009"));
+        assert_eq!(strategy.bucket(&decimal("9015.85"), false), decimal("9018"));
+        assert_eq!(strategy.bucket(&decimal("8999.999999999"), false), decimal("9000"));
+        assert_eq!(strategy.bucket(&decimal("8990"), true), decimal("8982"));
+        assert_eq!(strategy.next_bucket(&decimal("9009"), false), decimal("9000"));
+    }
+
+    #[test]
+    fn test_logarithmic_buckets() {
+        let strategy = GroupingStrategy::Logarithmic { buckets_per_decade: 10 };
+        assert_eq!(strategy.bucket(&decimal("9015.85"), true), decimal("7943.28"));
+        assert_eq!(strategy.bucket(&decimal("9015.85"), false), decimal("10000"));
+        assert_eq!(strategy.bucket(&decimal("0.0000123"), true), decimal("0.00001"));
+        assert_eq!(strategy.bucket(&decimal("0.0000123"), false), decimal("0.0000125893"));
+        assert_eq!(strategy.bucket(&decimal("1000"), true), strategy.bucket(&decimal("1000"), false));
+        assert_eq!(strategy.next_bucket(&decimal("7943.28"), true), decimal("10000"));
+        assert_eq!(strategy.next_bucket(&decimal("0.00001"), false), decimal("0.00000794328"));
+        assert!(GroupingStrategy::Logarithmic { buckets_per_decade: 0 }.validate().is_err());
+    }
+
+    #[test]
+    fn test_book_with_logarithmic_grouping() {
+        let mut book = OrderBook::new("ADA", 0);
+        book.set_grouping_strategy(GroupingStrategy::Logarithmic { buckets_per_decade: 10 }).unwrap();
+        book.add_level_str(OrderType::Bid, "0.0000123", "100", 1).unwrap();
+        book.add_level_str(OrderType::Bid, "0.0000120", "50", 2).unwrap();
+        book.add_level_str(OrderType::Ask, "0.0000131", "10", 3).unwrap();
+        book.remove_level_str(OrderType::Bid, "0.0000120", 4).unwrap();
+        assert!(book.audit().is_clean());
+        assert_eq!(book.get_group_size(), 0.0);
+        assert_eq!(book.grouped_bids.get(&decimal("0.00001")), Some(&decimal("100")));
+        assert_eq!(book.grouped_asks.get(&decimal("0.0000158489")), Some(&decimal("10")));
+
+        // buckets step down from the one holding the grouped mid price
+        let snapshot = book.get_grouped_snapshot_new(2);
+        assert_eq!(snapshot.bids.iter().map(|level| level.price).collect::<Vec<f64>>(), vec![0.0000125893, 0.00001]);
+        assert_eq!(snapshot.bids[1].total_size, 100.0);
+    }
+
+    #[test]
+    fn test_nice_group_sizes() {
+        assert_eq!(nice_group_sizes(0.01, 1.0, NiceSequence::OneTwoFive), vec![0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0]);
+        assert_eq!(nice_group_sizes(0.01, 1.0, NiceSequence::OneTwoHalfFive), vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]);
+        assert_eq!(nice_group_sizes(1e-8, 3e-8, NiceSequence::OneTwoHalfFive), vec![1e-8, 2.5e-8]);
+        assert!(nice_group_sizes(1.0, 0.5, NiceSequence::OneTwoFive).is_empty());
+    }
+
+    #[test]
+    fn test_suggest_group_sizes() {
+        let bytes = std::fs::read("snapshots/Binance:BTC_USDT").unwrap();
+        let mut book = OrderBook::from(SnapshotMessage::decode(bytes).unwrap());
+        assert_eq!(book.tick_size(), Some(0.01));
+
+        let suggestions = book.suggest_group_sizes(2.0, NiceSequence::OneTwoHalfFive);
+        assert_eq!(suggestions[0], 0.01);
+        let counts = suggestions.iter().map(|group_size| book.count_groups(*group_size, 2.0)).collect::<Vec<usize>>();
+        assert!(counts.windows(2).all(|pair| pair[0] != pair[1]));
+
+        let group_size = book.group_size_for_buckets(20, 2.0, NiceSequence::OneTwoHalfFive).unwrap();
+        let distance = |group_size: &f64| (book.count_groups(*group_size, 2.0) as i64 - 20).abs();
+        assert_eq!(distance(&group_size), suggestions.iter().map(distance).min().unwrap());
+
+        book.set_tick_size(Some(0.1));
+        let suggestions = book.suggest_group_sizes(2.0, NiceSequence::OneTwoHalfFive);
+        assert_eq!(suggestions[0], 0.1);
+        assert!(!suggestions.contains(&0.25));
+        assert!(OrderBook::new("instrument", 0).suggest_group_sizes(2.0, NiceSequence::OneTwoFive).is_empty());
+    }
+}
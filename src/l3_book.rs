@@ -0,0 +1,323 @@
+extern crate stock_messages;
+
+pub mod l3_book {
+    use crate::book::book::{Level, OrderBook, OrderType, Price, Size};
+    use crate::book_utils::book::to_plain_string;
+    use crate::error::error::BookError;
+    use bigdecimal::BigDecimal;
+    use num_traits::identities::Zero;
+    use std::collections::{BTreeMap, HashMap, VecDeque};
+    use std::ops::{Add, Mul};
+    use stock_messages::stock_messages::SnapshotMessage;
+
+    pub type OrderId = String;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Order {
+        pub id: OrderId,
+        pub side: OrderType,
+        pub price: Price,
+        pub size: Size,
+        // sequence at which the order joined the back of its price queue
+        pub sequence: u64,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum OrderEvent {
+        Add { id: OrderId, side: OrderType, price: Price, size: Size },
+        Modify { id: OrderId, price: Price, size: Size },
+        Cancel { id: OrderId },
+        Execute { id: OrderId, size: Size },
+    }
+
+    /// Market-by-order book. Every resting order is kept in a FIFO queue at its
+    /// price, and the aggregated `OrderBook` view is derived on demand.
+    #[derive(Debug, Clone)]
+    pub struct L3OrderBook {
+        pub instrument: String,
+        pub sequence: u64,
+        orders: HashMap<OrderId, Order>,
+        bids: BTreeMap<Price, VecDeque<OrderId>>,
+        asks: BTreeMap<Price, VecDeque<OrderId>>,
+    }
+
+    impl L3OrderBook {
+        pub fn new(instrument: &str, sequence: u64) -> L3OrderBook {
+            L3OrderBook {
+                instrument: String::from(instrument),
+                sequence,
+                orders: HashMap::new(),
+                bids: BTreeMap::new(),
+                asks: BTreeMap::new(),
+            }
+        }
+
+        pub fn apply(&mut self, event: OrderEvent, sequence: u64) -> Result<(), BookError> {
+            match event {
+                OrderEvent::Add { id, side, price, size } => self.add_order(id, side, price, size, sequence),
+                OrderEvent::Modify { id, price, size } => self.modify_order(&id, price, size, sequence),
+                OrderEvent::Cancel { id } => self.cancel_order(&id, sequence).map(|_| ()),
+                OrderEvent::Execute { id, size } => self.execute_order(&id, size, sequence),
+            }
+        }
+
+        pub fn add_order(&mut self, id: OrderId, side: OrderType, price: Price, size: Size, sequence: u64) -> Result<(), BookError> {
+            if self.orders.contains_key(&id) {
+                return Err(BookError::DuplicateOrder(id));
+            }
+            if price <= BigDecimal::zero() {
+                return Err(BookError::InvalidPrice(to_plain_string(&price)));
+            }
+            if size <= BigDecimal::zero() {
+                return Err(BookError::InvalidSize(to_plain_string(&size)));
+            }
+            self.sequence = sequence;
+            self.queues_mut(side)
+                .entry(price.clone())
+                .or_default()
+                .push_back(id.clone());
+            self.orders.insert(id.clone(), Order { id, side, price, size, sequence });
+            Ok(())
+        }
+
+        // A size decrease at the same price keeps queue priority; a price
+        // change or a size increase sends the order to the back of the queue.
+        pub fn modify_order(&mut self, id: &str, price: Price, size: Size, sequence: u64) -> Result<(), BookError> {
+            if size <= BigDecimal::zero() {
+                return self.cancel_order(id, sequence).map(|_| ());
+            }
+            if price <= BigDecimal::zero() {
+                return Err(BookError::InvalidPrice(to_plain_string(&price)));
+            }
+            let keeps_priority = match self.orders.get(id) {
+                Some(order) => order.price == price && size <= order.size,
+                None => return Err(BookError::UnknownOrder(id.to_string())),
+            };
+            self.sequence = sequence;
+            if keeps_priority {
+                if let Some(order) = self.orders.get_mut(id) {
+                    order.size = size;
+                }
+                return Ok(());
+            }
+            let side = self.cancel_order(id, sequence)?.side;
+            self.add_order(id.to_string(), side, price, size, sequence)
+        }
+
+        pub fn cancel_order(&mut self, id: &str, sequence: u64) -> Result<Order, BookError> {
+            let order = self.orders.remove(id).ok_or_else(|| BookError::UnknownOrder(id.to_string()))?;
+            self.sequence = sequence;
+            let queues = self.queues_mut(order.side);
+            if let Some(queue) = queues.get_mut(&order.price) {
+                queue.retain(|queued| queued != id);
+                if queue.is_empty() {
+                    queues.remove(&order.price);
+                }
+            }
+            Ok(order)
+        }
+
+        // Fails with `InvalidSize` unless the size is positive and at most the
+        // resting size.
+        pub fn execute_order(&mut self, id: &str, size: Size, sequence: u64) -> Result<(), BookError> {
+            if size <= BigDecimal::zero() {
+                return Err(BookError::InvalidSize(to_plain_string(&size)));
+            }
+            let remaining = match self.orders.get_mut(id) {
+                Some(order) if size <= order.size => {
+                    order.size -= size;
+                    order.size.clone()
+                }
+                Some(_) => return Err(BookError::InvalidSize(to_plain_string(&size))),
+                None => return Err(BookError::UnknownOrder(id.to_string())),
+            };
+            self.sequence = sequence;
+            if remaining.is_zero() {
+                self.cancel_order(id, sequence)?;
+            }
+            Ok(())
+        }
+
+        pub fn get_order(&self, id: &str) -> Option<&Order> {
+            self.orders.get(id)
+        }
+
+        pub fn order_count(&self) -> usize {
+            self.orders.len()
+        }
+
+        /// Orders resting at `price` in time priority.
+        pub fn get_queue(&self, side: OrderType, price: &Price) -> Vec<&Order> {
+            self.queues(side)
+                .get(price)
+                .map(|queue| queue.iter().filter_map(|id| self.orders.get(id)).collect())
+                .unwrap_or_default()
+        }
+
+        pub fn queue_position(&self, id: &str) -> Option<usize> {
+            let order = self.orders.get(id)?;
+            self.queues(order.side).get(&order.price)?.iter().position(|queued| queued == id)
+        }
+
+        pub fn get_levels(&self, side: OrderType) -> BTreeMap<Price, Level> {
+            self.queues(side)
+                .iter()
+                .map(|(price, queue)| {
+                    let size = queue
+                        .iter()
+                        .filter_map(|id| self.orders.get(id))
+                        .fold(BigDecimal::zero(), |total, order| total.add(&order.size));
+                    (price.clone(), Level::from_decimal(price.clone(), size))
+                })
+                .collect()
+        }
+
+        /// Aggregates the orders into a level book with totals and groupings.
+        pub fn to_order_book(&self) -> OrderBook {
+            let mut book = OrderBook::new(&self.instrument, self.sequence);
+            book.bids = self.get_levels(OrderType::Bid);
+            book.asks = self.get_levels(OrderType::Ask);
+            book.bids_total = book.bids.values().fold(BigDecimal::zero(), |total, level| total.add(&level.size));
+            book.asks_total = book.asks.values().fold(BigDecimal::zero(), |total, level| total.add(&level.size));
+            book.bids_value_total = book.bids.values().fold(BigDecimal::zero(), |total, level| total.add(&level.value));
+            book.asks_value_total = book.asks.values().fold(BigDecimal::zero(), |total, level| total.add(&level.value));
+            book.refresh_groupings();
+            book
+        }
+
+        pub fn total_size(&self, side: OrderType) -> Size {
+            self.orders
+                .values()
+                .filter(|order| order.side == side)
+                .fold(BigDecimal::zero(), |total, order| total.add(&order.size))
+        }
+
+        pub fn total_value(&self, side: OrderType) -> BigDecimal {
+            self.orders
+                .values()
+                .filter(|order| order.side == side)
+                .fold(BigDecimal::zero(), |total, order| total.add((&order.price).mul(&order.size)))
+        }
+
+        fn queues(&self, side: OrderType) -> &BTreeMap<Price, VecDeque<OrderId>> {
+            match side {
+                OrderType::Bid => &self.bids,
+                OrderType::Ask => &self.asks,
+            }
+        }
+
+        fn queues_mut(&mut self, side: OrderType) -> &mut BTreeMap<Price, VecDeque<OrderId>> {
+            match side {
+                OrderType::Bid => &mut self.bids,
+                OrderType::Ask => &mut self.asks,
+            }
+        }
+    }
+
+    impl Into<SnapshotMessage> for &L3OrderBook {
+        fn into(self) -> SnapshotMessage {
+            self.to_order_book().into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::l3_book::{L3OrderBook, OrderEvent};
+    use crate::book::book::OrderType;
+    use crate::error::error::BookError;
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+    use stock_messages::stock_messages::SnapshotMessage;
+
+    fn decimal(value: &str) -> BigDecimal {
+        BigDecimal::from_str(value).unwrap()
+    }
+
+    fn create_book() -> L3OrderBook {
+        let mut book = L3OrderBook::new("instrument", 0);
+        book.add_order("a".to_string(), OrderType::Bid, decimal("100.5"), decimal("1"), 1).unwrap();
+        book.add_order("b".to_string(), OrderType::Bid, decimal("100.5"), decimal("2"), 2).unwrap();
+        book.add_order("c".to_string(), OrderType::Bid, decimal("100.5"), decimal("3"), 3).unwrap();
+        book.add_order("d".to_string(), OrderType::Ask, decimal("101"), decimal("4"), 4).unwrap();
+        book
+    }
+
+    #[test]
+    fn test_fifo_priority() {
+        let mut book = create_book();
+        assert_eq!(book.queue_position("a"), Some(0));
+        assert_eq!(
+            book.add_order("a".to_string(), OrderType::Bid, decimal("99"), decimal("1"), 5),
+            Err(BookError::DuplicateOrder("a".to_string()))
+        );
+
+        // decrease keeps priority
+        assert!(book.modify_order("a", decimal("100.5"), decimal("0.5"), 5).is_ok());
+        assert_eq!(book.queue_position("a"), Some(0));
+
+        // increase loses priority
+        assert!(book.modify_order("a", decimal("100.5"), decimal("5"), 6).is_ok());
+        assert_eq!(book.queue_position("a"), Some(2));
+        assert_eq!(book.get_order("a").unwrap().sequence, 6);
+
+        let ids = book
+            .get_queue(OrderType::Bid, &decimal("100.5"))
+            .iter()
+            .map(|order| order.id.clone())
+            .collect::<Vec<String>>();
+        assert_eq!(ids, vec!["b", "c", "a"]);
+    }
+
+    #[test]
+    fn test_execute_and_cancel() {
+        let mut book = create_book();
+        assert!(book.apply(OrderEvent::Execute { id: "b".to_string(), size: decimal("1.5") }, 5).is_ok());
+        assert_eq!(book.get_order("b").unwrap().size, decimal("0.5"));
+        assert_eq!(
+            book.apply(OrderEvent::Execute { id: "b".to_string(), size: decimal("1") }, 6),
+            Err(BookError::InvalidSize("1".to_string()))
+        );
+        assert_eq!(
+            book.apply(OrderEvent::Execute { id: "b".to_string(), size: decimal("-0.5") }, 6),
+            Err(BookError::InvalidSize("-0.5".to_string()))
+        );
+        assert_eq!(
+            book.apply(OrderEvent::Execute { id: "b".to_string(), size: decimal("0") }, 6),
+            Err(BookError::InvalidSize("0".to_string()))
+        );
+        assert_eq!(book.get_order("b").unwrap().size, decimal("0.5"));
+        assert!(book.apply(OrderEvent::Execute { id: "b".to_string(), size: decimal("0.5") }, 6).is_ok());
+        assert!(book.get_order("b").is_none());
+
+        assert!(book.apply(OrderEvent::Cancel { id: "d".to_string() }, 7).is_ok());
+        assert_eq!(
+            book.apply(OrderEvent::Cancel { id: "d".to_string() }, 8),
+            Err(BookError::UnknownOrder("d".to_string()))
+        );
+        assert!(book.get_levels(OrderType::Ask).is_empty());
+        assert_eq!(book.order_count(), 2);
+        assert_eq!(book.sequence, 7);
+    }
+
+    #[test]
+    fn test_aggregated_view() {
+        let mut book = create_book();
+        book.apply(
+            OrderEvent::Modify { id: "c".to_string(), price: decimal("100"), size: decimal("3") },
+            5,
+        )
+        .unwrap();
+        let order_book = book.to_order_book();
+        assert_eq!(order_book.bids.len(), 2);
+        assert_eq!(order_book.bids[&decimal("100.5")].size, decimal("3"));
+        assert_eq!(order_book.bids[&decimal("100")].size, decimal("3"));
+        assert_eq!(order_book.bids_total, book.total_size(OrderType::Bid));
+        assert_eq!(order_book.asks_value_total, decimal("404"));
+
+        let snapshot: SnapshotMessage = (&book).into();
+        assert_eq!(snapshot.bids.len(), 2);
+        assert_eq!(snapshot.asks[0].total_size, 4.0);
+        assert_eq!(snapshot.source_sequence, 5);
+    }
+}
@@ -3,64 +3,164 @@ extern crate prost;
 extern crate stock_messages;
 extern crate itertools;
 
+mod audit;
 mod book;
+mod book_handle;
 mod book_utils;
+mod checksum;
+mod consumer;
+mod error;
+mod grouping;
+mod tick_book;
+mod l3_book;
+mod memory;
+mod subscriptions;
+mod binance;
 
-use std::{collections::{HashMap}, convert::TryFrom, cell::RefCell};
+use std::{collections::{HashMap}, cell::RefCell};
 extern crate wasm_bindgen;
 
 use bigdecimal::BigDecimal;
+use js_sys::{Array, Float64Array, Object, Reflect};
 use num_traits::{ToPrimitive, FromPrimitive};
+use prost::Message;
+use stock_messages::stock_messages::SnapshotMessage;
 use wasm_bindgen::prelude::*;
 
-pub use book::book::{OrderBook, OrderType, Level, OrderBookSnapshot};
+pub use book_handle::book_handle::{BookHandle, LADDER_HEADER_LEN};
+use book_handle::book_handle::{call_callbacks, to_object};
+pub use book::book::{OrderBook, OrderType, Level, OrderBookSnapshot, DecimalLevelUpdate, DecimalPriceLevel, DecimalSnapshot, RangeUpdate, SyncState, SyncEvent, UpdateMode, NegativeLevelPolicy, CrossState, CrossPolicy, LevelChange, LevelEvent};
+pub use tick_book::tick_book::{TickOrderBook, TickSpec, Ticks, Lots};
+pub use l3_book::l3_book::{L3OrderBook, Order, OrderEvent, OrderId};
+pub use binance::binance::{BinanceDepthSync, DepthSnapshot, DepthUpdateEvent};
+pub use error::error::BookError;
+pub use checksum::checksum::ChecksumAlgorithm;
+pub use consumer::consumer::{GroupedSnapshotChanges, SnapshotConsumer};
+pub use audit::audit::{AuditField, AuditReport, Divergence};
+pub use book_utils::book::group;
+pub use grouping::grouping::{nice_group_sizes, GroupingStrategy, GroupingView, NiceSequence};
+pub use memory::memory::BookMemory;
+pub use subscriptions::subscriptions::{BookEvent, BookEventKind, BookWatcher};
 
 thread_local! {
-    static BOOK_MAP: RefCell<HashMap<u32, OrderBook>> = RefCell::new(HashMap::new());
-}
-
-#[wasm_bindgen]
-pub fn update_snapshot(book_id:u32, bytes: Vec<u8>) -> bool {
-    let new_book: Result<OrderBook, &str> = OrderBook::try_from(bytes);
-    if let Ok(book) = new_book {
-        BOOK_MAP.with(|map_ref| {
-            let mut map = map_ref.borrow_mut();
-            map.insert(book_id, book);
-        });
-        return true;
-    } else {
-        let book = OrderBook::new("", 0);
-        BOOK_MAP.with(|map_ref| {
-            let mut map = map_ref.borrow_mut();
-            map.insert(book_id, book);
-        });
-        return true;
+    static BOOK_MAP: RefCell<HashMap<u32, BookHandle>> = RefCell::new(HashMap::new());
+}
+
+/// Errors are thrown as JS `Error`s named `BookError`, with the `code` and
+/// variant `kind` of the `BookError`, `bookId` when thrown by an id-based
+/// function, and the `expected` and `received` sequences of sequence errors.
+impl From<BookError> for JsValue {
+    fn from(error: BookError) -> JsValue {
+        let js_error = js_sys::Error::new(&error.to_string());
+        js_error.set_name("BookError");
+        let mut fields: Vec<(&str, JsValue)> = vec![("code", error.code().into()), ("kind", error.name().into())];
+        match error {
+            BookError::StaleSequence { expected, received } | BookError::SequenceGap { expected, received } => {
+                fields.push(("expected", (expected as f64).into()));
+                fields.push(("received", (received as f64).into()));
+            }
+            BookError::ChecksumMismatch { expected, computed } => {
+                fields.push(("expected", expected.into()));
+                fields.push(("computed", computed.into()));
+            }
+            BookError::UnknownBook(book_id) => fields.push(("bookId", book_id.into())),
+            _ => {}
+        }
+        for (name, value) in fields.iter() {
+            // setting a property on a fresh Error cannot fail
+            let _ = Reflect::set(&js_error, &JsValue::from_str(name), value);
+        }
+        js_error.into()
     }
 }
 
-#[wasm_bindgen]
-pub fn update_book_level(book_id: u32, bytes: Vec<u8>) -> bool {
-    return BOOK_MAP.with(|map_ref| {
+fn with_book_id(error: JsValue, book_id: u32) -> JsValue {
+    if error.is_object() {
+        let _ = Reflect::set(&error, &JsValue::from_str("bookId"), &book_id.into());
+    }
+    error
+}
+
+// The functions below address books by id for callers predating the
+// `OrderBook` class, each one forwards to the class method of the same name.
+// Books in the map queue the callbacks their updates fire, which are called
+// once the map is released so they can use these functions themselves. An
+// error of the call itself is thrown in preference to one from a callback.
+fn with_map<T>(book_id: u32, f: impl FnOnce(&mut HashMap<u32, BookHandle>) -> Result<T, JsValue>) -> Result<T, JsValue> {
+    let (result, calls) = BOOK_MAP.with(|map_ref| {
         let mut map = map_ref.borrow_mut();
-        let book = map.get_mut(&book_id);
-        if let Some(book) = book {
-            return book.update_level(bytes);
-        }
-        return false;
+        let result = f(&mut map);
+        let calls = map.get_mut(&book_id).map_or_else(Vec::new, |handle| handle.take_deferred_calls());
+        (result, calls)
     });
+    let called = call_callbacks(calls);
+    let value = result.map_err(|error| with_book_id(error, book_id))?;
+    called.map_err(|error| with_book_id(error, book_id))?;
+    Ok(value)
 }
 
-//use this only for testing
+fn with_book<T>(book_id: u32, f: impl FnOnce(&mut BookHandle) -> Result<T, JsValue>) -> Result<T, JsValue> {
+    with_map(book_id, |map| match map.get_mut(&book_id) {
+        Some(handle) => f(handle),
+        None => Err(BookError::UnknownBook(book_id).into()),
+    })
+}
+
+fn deferring_callbacks(mut handle: BookHandle) -> BookHandle {
+    handle.defer_callbacks = true;
+    handle
+}
+
+/// Throws if the snapshot cannot be decoded, or if it does not reach the
+/// updates buffered for an existing book.
 #[wasm_bindgen]
-pub fn update_book_level_struct(book_id: u32, side:u32, price: f64, size: f64) -> bool {
-    return BOOK_MAP.with(|map_ref| {
-        let mut map = map_ref.borrow_mut();
-        let book = map.get_mut(&book_id);
-        if let Some(book) = book {
-            return book.update_level_message(stock_messages::stock_messages::LevelUpdate { r#type: 0, exchange: "".to_string(), price: price, product_id: "".to_string(), sequence: (book.sequence + 1) as i32, side: side as i32, size: size, time: 0, count: 0 });
+pub fn update_snapshot(book_id:u32, bytes: Vec<u8>) -> Result<(), JsValue> {
+    with_map(book_id, |map| match map.get_mut(&book_id) {
+        Some(handle) => handle.update_snapshot(bytes),
+        None => {
+            map.insert(book_id, deferring_callbacks(BookHandle::from_snapshot(bytes)?));
+            Ok(())
         }
-        return false;
-    });
+    })
+}
+
+/// Replaces the top `depth` levels of the book with a partial snapshot while
+/// keeping its settings, creating the book if needed. Returns the implied
+/// level events flattened as `[side, change, price, size, ...]` with side
+/// Bid = 1 / Ask = 2 and change Add = 0 / Change = 1 / Delete = 2.
+#[wasm_bindgen]
+pub fn update_partial_snapshot(book_id: u32, bytes: Vec<u8>, depth: usize) -> Result<Vec<f64>, JsValue> {
+    let snapshot = SnapshotMessage::decode(bytes)
+        .map_err(|error| with_book_id(BookError::from(error).into(), book_id))?;
+    with_map(book_id, |map| {
+        map.entry(book_id)
+            .or_insert_with(|| deferring_callbacks(BookHandle::new(&snapshot.product_id)))
+            .replace_top_levels(snapshot, depth)
+    })
+}
+
+/// Throws on a malformed frame, a sequence gap or an unknown book. Returns
+/// false if the update is stale and was ignored.
+#[wasm_bindgen]
+pub fn update_book_level(book_id: u32, bytes: Vec<u8>) -> Result<bool, JsValue> {
+    with_book(book_id, |handle| handle.update_level(bytes))
+}
+
+/// Applies a length-delimited stream of `LevelUpdate`s in one call. Nothing is
+/// applied if any update is malformed or out of sequence.
+#[wasm_bindgen]
+pub fn update_book_levels(book_id: u32, bytes: Vec<u8>) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.update_levels(bytes))
+}
+
+//use this only for testing
+#[wasm_bindgen]
+pub fn update_book_level_struct(book_id: u32, side:u32, price: f64, size: f64) -> Result<(), JsValue> {
+    with_book(book_id, |handle| {
+        let book = &mut handle.book;
+        book.update_level_message(stock_messages::stock_messages::LevelUpdate { r#type: 0, exchange: "".to_string(), price: price, product_id: "".to_string(), sequence: (book.sequence + 1) as i32, side: side as i32, size: size, time: 0, count: 0 })?;
+        Ok(())
+    })
 }
 
 #[wasm_bindgen]
@@ -73,58 +173,267 @@ pub fn has_book(book_id: u32) -> bool {
     return result;
 }
 
+/// Drops the book and everything it holds, including the buffer behind views
+/// from `get_grouped_ladder`. Returns false for an unknown book.
 #[wasm_bindgen]
-pub fn get_snapshot(book_id: u32) -> Vec<u8> {
-    let result = BOOK_MAP.with(|map_ref| {
-        let map = map_ref.borrow();
-        let book = map.get(&book_id);
-        book.map_or(Vec::new(), |book| book.into())
-    });
-    return result;
+pub fn remove_book(book_id: u32) -> bool {
+    BOOK_MAP.with(|map_ref| map_ref.borrow_mut().remove(&book_id).is_some())
 }
 
+/// Drops every book, returning how many there were. Views from
+/// `get_grouped_ladder` must not be read afterwards.
 #[wasm_bindgen]
-pub fn get_grouped_snapshot(book_id: u32, count:usize) -> Vec<f64> {
-    let mut out:Vec<f64> = Vec::new();
-    let result = BOOK_MAP.with(|map_ref| {
+pub fn clear_books() -> usize {
+    BOOK_MAP.with(|map_ref| {
+        let mut map = map_ref.borrow_mut();
+        let count = map.len();
+        *map = HashMap::new();
+        count
+    })
+}
+
+/// `{ id, instrument, sequence }` for every book, ordered by id.
+#[wasm_bindgen]
+pub fn list_books() -> Result<Array, JsValue> {
+    BOOK_MAP.with(|map_ref| {
         let map = map_ref.borrow();
-        let book = map.get(&book_id);
-        if let Some(book) = book {
-            let snapshot = book.get_grouped_snapshot_new(count);
-            for level in snapshot.asks.iter().rev() {
-                out.push(level.price);
-                out.push(level.total_size);
-            }
-            out.push(99999.99999);
-            out.push(99999.99999);
-            for level in snapshot.bids.iter() {
-                out.push(level.price);
-                out.push(level.total_size);
-            }
+        let mut ids = map.keys().cloned().collect::<Vec<u32>>();
+        ids.sort_unstable();
+        let books = Array::new();
+        for id in ids {
+            let handle = &map[&id];
+            let book = to_object(&[
+                ("id", id.into()),
+                ("instrument", handle.instrument().into()),
+                ("sequence", handle.sequence().into()),
+            ])?;
+            books.push(&book);
         }
-    });
-    return out;
+        Ok(books)
+    })
 }
 
+/// Approximate memory held by the book, see the `memory_usage` method of
+/// `OrderBook` for the fields.
 #[wasm_bindgen]
-pub fn get_grouping_bucket(decimal:f64, group_size:f64, bid: bool) -> f64 {
-    let decimal = BigDecimal::from_f64(decimal).unwrap_or_default();
-    return book_utils::book::group(decimal, group_size, bid).to_f64().unwrap_or_default();
+pub fn get_book_memory(book_id: u32) -> Result<Object, JsValue> {
+    with_book(book_id, |handle| handle.memory_usage())
 }
 
+/// Approximate bytes held by all books. Grouping keeps no global cache, so
+/// this is the memory of the module's books.
+#[wasm_bindgen]
+pub fn get_total_memory() -> f64 {
+    BOOK_MAP.with(|map_ref| {
+        let map = map_ref.borrow();
+        map.values().map(|handle| handle.book_memory().bytes).sum::<usize>() as f64
+    })
+}
 
+/// Returns the `SyncState` of the book as its index (Synced = 0, Gapped = 1,
+/// AwaitingSnapshot = 2, Resyncing = 3).
 #[wasm_bindgen]
-pub fn set_group_size(book_id: u32, size: f64) {
-    let result = BOOK_MAP.with(|map_ref| {
-        let mut map = map_ref.borrow_mut();
-        let book = map.get_mut(&book_id);
-        if let Some(orderbook) = book {
-            if size != 0.0 {
-                orderbook.set_group_size(size);
-            }
-        };
-    });
-    return result;
+pub fn get_sync_state(book_id: u32) -> Result<u32, JsValue> {
+    with_book(book_id, |handle| Ok(handle.sync_state()))
+}
+
+#[wasm_bindgen]
+pub fn is_book_stale(book_id: u32) -> Result<bool, JsValue> {
+    with_book(book_id, |handle| Ok(handle.is_stale()))
+}
+
+#[wasm_bindgen]
+pub fn get_snapshot(book_id: u32) -> Result<Vec<u8>, JsValue> {
+    with_book(book_id, |handle| Ok(handle.snapshot()))
+}
+
+#[wasm_bindgen]
+pub fn get_grouped_snapshot(book_id: u32, count:usize) -> Result<Vec<f64>, JsValue> {
+    with_book(book_id, |handle| Ok(handle.grouped_snapshot(count)))
+}
+
+/// Same ladder as `get_grouped_snapshot` written into a buffer kept by the
+/// book, see the `grouped_ladder` method of `OrderBook` for the layout.
+/// `remove_book` and `clear_books` free the buffer without detaching the
+/// returned view, so it must not be read after the book is removed.
+#[wasm_bindgen]
+pub fn get_grouped_ladder(book_id: u32, count: usize) -> Result<Float64Array, JsValue> {
+    with_book(book_id, |handle| Ok(handle.grouped_ladder(count)))
+}
+
+/// Registers a reader of `get_grouped_snapshot_changes`, over the named
+/// grouping view or the default grouping if `view` is undefined.
+#[wasm_bindgen]
+pub fn register_snapshot_consumer(book_id: u32, view: Option<String>) -> Result<u32, JsValue> {
+    with_book(book_id, |handle| handle.register_snapshot_consumer(view))
+}
+
+#[wasm_bindgen]
+pub fn unregister_snapshot_consumer(book_id: u32, consumer: u32) -> Result<bool, JsValue> {
+    with_book(book_id, |handle| Ok(handle.unregister_snapshot_consumer(consumer)))
+}
+
+/// Grouped levels changed since the consumer's previous call, laid out like
+/// `get_grouped_snapshot` after a leading flag that is 1 when the whole
+/// ladder is included and replaces the previous one, 0 when only the changed
+/// buckets are.
+#[wasm_bindgen]
+pub fn get_grouped_snapshot_changes(book_id: u32, consumer: u32, count: usize) -> Result<Vec<f64>, JsValue> {
+    with_book(book_id, |handle| handle.grouped_snapshot_changes(consumer, count))
+}
+
+/// Adds or replaces a named grouping view kept up to date with the book.
+#[wasm_bindgen]
+pub fn add_grouping_view(book_id: u32, name: String, group_size: f64) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.add_grouping_view(&name, group_size))
+}
+
+/// Like `add_grouping_view` with a strategy, see `set_grouping_strategy`.
+#[wasm_bindgen]
+pub fn add_grouping_view_strategy(book_id: u32, name: String, strategy: u32, value: f64, reference: f64) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.add_grouping_view_strategy(&name, strategy, value, reference))
+}
+
+/// Sets how the default grouping buckets prices. `strategy` is the index of
+/// `GroupingStrategy` (Absolute = 0 with `value` as the group size,
+/// BasisPoints = 1 with `value` basis points of `reference`, Logarithmic = 2
+/// with `value` buckets per decade).
+#[wasm_bindgen]
+pub fn set_grouping_strategy(book_id: u32, strategy: u32, value: f64, reference: f64) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.set_grouping_strategy(strategy, value, reference))
+}
+
+/// Returns false if the book has no view with this name.
+#[wasm_bindgen]
+pub fn remove_grouping_view(book_id: u32, name: String) -> Result<bool, JsValue> {
+    with_book(book_id, |handle| Ok(handle.remove_grouping_view(&name)))
+}
+
+/// Grouped snapshot of a named view, laid out like `get_grouped_snapshot`.
+#[wasm_bindgen]
+pub fn get_view_grouped_snapshot(book_id: u32, name: String, count: usize) -> Result<Vec<f64>, JsValue> {
+    with_book(book_id, |handle| handle.view_grouped_snapshot(&name, count))
+}
+
+#[wasm_bindgen]
+pub fn get_grouping_bucket(decimal:f64, group_size:f64, bid: bool) -> Result<f64, JsValue> {
+    if !(group_size.is_finite() && group_size > 0.0) {
+        return Err(BookError::InvalidSize(group_size.to_string()).into());
+    }
+    let price = BigDecimal::from_f64(decimal).ok_or_else(|| BookError::InvalidPrice(decimal.to_string()))?;
+    Ok(book_utils::book::group(price, group_size, bid).to_f64().unwrap_or_default())
+}
+
+
+#[wasm_bindgen]
+pub fn set_group_size(book_id: u32, size: f64) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.set_group_size(size))
+}
+
+/// 0 clears the tick size, which is then inferred from the levels.
+#[wasm_bindgen]
+pub fn set_tick_size(book_id: u32, tick_size: f64) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.set_tick_size(tick_size))
+}
+
+/// Group sizes to offer for the book, from the tick size up to `max_percent`
+/// of mid. `sequence` is the index of `NiceSequence` (1-2-5 = 0, 1-2.5-5 = 1).
+#[wasm_bindgen]
+pub fn suggest_group_sizes(book_id: u32, max_percent: f64, sequence: u32) -> Result<Vec<f64>, JsValue> {
+    with_book(book_id, |handle| handle.suggest_group_sizes(max_percent, sequence))
+}
+
+/// The suggested group size giving about `buckets` non-empty groups within
+/// `percent` of mid, or 0 if the book is empty.
+#[wasm_bindgen]
+pub fn group_size_for_buckets(book_id: u32, buckets: usize, percent: f64, sequence: u32) -> Result<f64, JsValue> {
+    with_book(book_id, |handle| handle.group_size_for_buckets(buckets, percent, sequence))
+}
+
+/// Switches the book between absolute sizes (`delta = false`) and signed
+/// size deltas (`delta = true`).
+#[wasm_bindgen]
+pub fn set_update_mode(book_id: u32, delta: bool) -> Result<(), JsValue> {
+    with_book(book_id, |handle| {
+        handle.set_update_mode(delta);
+        Ok(())
+    })
+}
+
+/// `policy` is the index of `NegativeLevelPolicy` (Remove = 0, Reject = 1,
+/// RequestSnapshot = 2).
+#[wasm_bindgen]
+pub fn set_negative_level_policy(book_id: u32, policy: u32) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.set_negative_level_policy(policy))
+}
+
+/// Returns the `CrossState` of the book as its index (Normal = 0, Locked = 1,
+/// Crossed = 2).
+#[wasm_bindgen]
+pub fn get_cross_state(book_id: u32) -> Result<u32, JsValue> {
+    with_book(book_id, |handle| Ok(handle.cross_state()))
+}
+
+/// `policy` is the index of `CrossPolicy` (FlagOnly = 0, DropOlderSide = 1,
+/// Resync = 2).
+#[wasm_bindgen]
+pub fn set_cross_policy(book_id: u32, policy: u32) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.set_cross_policy(policy))
+}
+
+/// `algorithm` is the index of `ChecksumAlgorithm` (Okx = 0, Kraken = 1,
+/// Bitfinex = 2), or -1 to stop verifying checksums.
+#[wasm_bindgen]
+pub fn set_checksum_algorithm(book_id: u32, algorithm: i32) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.set_checksum_algorithm(algorithm))
+}
+
+/// Verifies the checksum sent by the venue after an update. Throws and marks
+/// the book as awaiting a snapshot on a mismatch.
+#[wasm_bindgen]
+pub fn verify_checksum(book_id: u32, checksum: f64) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.verify_checksum(checksum))
+}
+
+/// Limits each side of the book to `max_depth` levels, 0 removes the limit.
+#[wasm_bindgen]
+pub fn set_max_depth(book_id: u32, max_depth: usize) -> Result<(), JsValue> {
+    with_book(book_id, |handle| {
+        handle.set_max_depth(max_depth);
+        Ok(())
+    })
+}
+
+/// Calls `callback` on events of the book, see the `subscribe` method of
+/// `OrderBook` for the kinds and arguments. Callbacks run after the update has
+/// released the books, so they may call any of these functions.
+#[wasm_bindgen]
+pub fn subscribe_book_event(book_id: u32, kind: u32, callback: js_sys::Function) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.subscribe(kind, callback))
+}
+
+#[wasm_bindgen]
+pub fn unsubscribe_book_event(book_id: u32, kind: u32) -> Result<bool, JsValue> {
+    with_book(book_id, |handle| handle.unsubscribe(kind))
+}
+
+/// Fires each kind of event at most once per `interval` milliseconds, 0 fires
+/// every event. Best price and spread events held back are coalesced, other
+/// kinds are queued.
+#[wasm_bindgen]
+pub fn set_event_throttle(book_id: u32, interval: f64) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.set_event_throttle(interval))
+}
+
+/// Levels reaching `size` fire LargeLevel events, 0 disables them.
+#[wasm_bindgen]
+pub fn set_large_level_size(book_id: u32, size: f64) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.set_large_level_size(size))
+}
+
+#[wasm_bindgen]
+pub fn flush_book_events(book_id: u32) -> Result<(), JsValue> {
+    with_book(book_id, |handle| handle.flush_events())
 }
 
 #[cfg(feature = "console_error_panic_hook")]
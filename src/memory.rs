@@ -0,0 +1,127 @@
+pub mod memory {
+    use crate::audit::audit::{AuditReport, Divergence};
+    use crate::book::book::{Level, LevelEvent, OrderBook, PendingUpdate, Price, Size, SyncEvent};
+    use bigdecimal::BigDecimal;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::mem::size_of;
+
+    // Rough per-entry cost of a `BTreeMap` node on top of the key and value.
+    const MAP_ENTRY_OVERHEAD: usize = 16;
+
+    /// Approximate memory held by a book: its levels, grouped levels and
+    /// views, buffered updates, events not drained yet and the buckets waiting
+    /// for snapshot consumers. Grouping keeps no global cache to add to it.
+    #[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+    pub struct BookMemory {
+        pub bid_levels: usize,
+        pub ask_levels: usize,
+        pub grouped_bid_levels: usize,
+        pub grouped_ask_levels: usize,
+        /// Grouped levels of all named grouping views.
+        pub view_levels: usize,
+        pub pending_updates: usize,
+        /// Sync events, audit reports and large level events not drained yet.
+        pub queued_events: usize,
+        /// Changed buckets waiting to be sent to snapshot consumers.
+        pub dirty_buckets: usize,
+        pub bytes: usize,
+    }
+
+    impl OrderBook {
+        pub fn memory_usage(&self) -> BookMemory {
+            let views = self.grouping_views().values();
+            let view_bytes = views
+                .clone()
+                .map(|view| grouped_bytes(&view.grouped_bids) + grouped_bytes(&view.grouped_asks))
+                .sum::<usize>();
+            let dirty_buckets = self
+                .snapshot_consumers()
+                .values()
+                .map(|consumer| consumer.dirty_bids.len() + consumer.dirty_asks.len())
+                .sum::<usize>();
+            BookMemory {
+                bid_levels: self.bids.len(),
+                ask_levels: self.asks.len(),
+                grouped_bid_levels: self.grouped_bids.len(),
+                grouped_ask_levels: self.grouped_asks.len(),
+                view_levels: views.map(|view| view.grouped_bids.len() + view.grouped_asks.len()).sum(),
+                pending_updates: self.pending_update_count(),
+                queued_events: self.sync_events.len() + self.audit_reports.len() + self.large_level_events.len(),
+                dirty_buckets,
+                bytes: size_of::<OrderBook>()
+                    + level_bytes(&self.bids)
+                    + level_bytes(&self.asks)
+                    + grouped_bytes(&self.grouped_bids)
+                    + grouped_bytes(&self.grouped_asks)
+                    + view_bytes
+                    + dirty_buckets * (size_of::<Price>() + MAP_ENTRY_OVERHEAD)
+                    + self.queue_bytes(),
+            }
+        }
+
+        fn queue_bytes(&self) -> usize {
+            let pending_bytes = self.pending_updates.len() * (size_of::<i32>() + size_of::<PendingUpdate>() + MAP_ENTRY_OVERHEAD);
+            let audit_bytes = self
+                .audit_reports
+                .iter()
+                .map(|report| size_of::<AuditReport>() + report.instrument.len() + report.divergences.len() * size_of::<Divergence>())
+                .sum::<usize>();
+            pending_bytes
+                + audit_bytes
+                + self.sync_events.capacity() * size_of::<SyncEvent>()
+                + self.large_level_events.capacity() * size_of::<LevelEvent>()
+        }
+    }
+
+    // Digits of a decimal live on the heap, 19 decimal digits to a u64.
+    fn decimal_bytes(decimal: &BigDecimal) -> usize {
+        (decimal.digits() as usize / 19 + 1) * 8
+    }
+
+    fn level_bytes(levels: &BTreeMap<Price, Level>) -> usize {
+        let entry = size_of::<Price>() + size_of::<Level>() + MAP_ENTRY_OVERHEAD;
+        levels
+            .iter()
+            .map(|(price, level)| entry + 2 * decimal_bytes(price) + decimal_bytes(&level.size) + decimal_bytes(&level.value))
+            .sum()
+    }
+
+    fn grouped_bytes(grouped: &BTreeMap<Price, Size>) -> usize {
+        let entry = size_of::<Price>() + size_of::<Size>() + MAP_ENTRY_OVERHEAD;
+        grouped
+            .iter()
+            .map(|(price, size)| entry + decimal_bytes(price) + decimal_bytes(size))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::book::book::{DecimalLevelUpdate, OrderBook, OrderType};
+    use crate::grouping::grouping::GroupingStrategy;
+
+    #[test]
+    fn test_memory_usage() {
+        let mut book = OrderBook::new("instrument", 0);
+        let empty = book.memory_usage();
+        book.add_level_str(OrderType::Bid, "100", "1", 1).unwrap();
+        book.add_level_str(OrderType::Bid, "99.5", "1", 2).unwrap();
+        book.add_level_str(OrderType::Ask, "101", "1", 3).unwrap();
+        book.add_grouping_view("tens", GroupingStrategy::Absolute(10.0)).unwrap();
+
+        let memory = book.memory_usage();
+        assert_eq!((memory.bid_levels, memory.ask_levels), (2, 1));
+        assert_eq!((memory.grouped_bid_levels, memory.grouped_ask_levels), (2, 1));
+        assert_eq!(memory.view_levels, 3);
+        assert!(memory.bytes > empty.bytes);
+
+        let before = memory.bytes;
+        // sequence 4 is missing, so the update is buffered
+        let update = DecimalLevelUpdate { side: 0, price: "98".to_string(), size: "1".to_string(), sequence: 5 };
+        book.update_level_decimal(update).unwrap_err();
+        let memory = book.memory_usage();
+        assert_eq!((memory.pending_updates, memory.queued_events), (1, 1));
+        assert!(memory.bytes > before);
+    }
+}
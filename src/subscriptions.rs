@@ -0,0 +1,261 @@
+pub mod subscriptions {
+    use crate::book::book::{OrderBook, OrderType, SyncState};
+    use num_traits::ToPrimitive;
+    use serde::{Deserialize, Serialize};
+
+    /// Kinds of book events that can be subscribed to.
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum BookEventKind {
+        BestPrice = 0,
+        Spread = 1,
+        SequenceGap = 2,
+        ResyncNeeded = 3,
+        LargeLevel = 4,
+    }
+
+    impl BookEventKind {
+        /// Whether only the latest event of the kind matters, so that events
+        /// held back by the throttle can be replaced rather than queued.
+        pub fn is_coalesced(self) -> bool {
+            match self {
+                BookEventKind::BestPrice | BookEventKind::Spread => true,
+                BookEventKind::SequenceGap | BookEventKind::ResyncNeeded | BookEventKind::LargeLevel => false,
+            }
+        }
+    }
+
+    const EVENT_KINDS: usize = 5;
+
+    /// Events of one kind the throttle holds back before dropping the oldest.
+    pub const MAX_HELD_EVENTS: usize = 1000;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum BookEvent {
+        /// The best bid or ask moved, 0 for an empty side.
+        BestPrice { best_bid: f64, best_ask: f64 },
+        /// The spread changed while both sides have levels.
+        Spread { spread: f64 },
+        /// An update arrived ahead of `sequence + 1`.
+        SequenceGap { sequence: u64 },
+        /// The book gave up waiting for a gap to fill and needs a snapshot.
+        ResyncNeeded { sequence: u64 },
+        /// A level reached the large level size of the book.
+        LargeLevel { side: OrderType, price: f64, size: f64 },
+    }
+
+    impl BookEvent {
+        pub fn kind(&self) -> BookEventKind {
+            match self {
+                BookEvent::BestPrice { .. } => BookEventKind::BestPrice,
+                BookEvent::Spread { .. } => BookEventKind::Spread,
+                BookEvent::SequenceGap { .. } => BookEventKind::SequenceGap,
+                BookEvent::ResyncNeeded { .. } => BookEventKind::ResyncNeeded,
+                BookEvent::LargeLevel { .. } => BookEventKind::LargeLevel,
+            }
+        }
+    }
+
+    /// Turns changes of a book into `BookEvent`s, letting each kind through at
+    /// most once per `interval` milliseconds. Held back best price and spread
+    /// events are coalesced into the latest one, while sequence gap, resync and
+    /// large level events are queued and let through together once the interval
+    /// of their kind has passed. An interval of 0 lets every event through.
+    #[derive(Debug, Clone, Default)]
+    pub struct BookWatcher {
+        pub interval: f64,
+        best_bid: f64,
+        best_ask: f64,
+        spread: Option<f64>,
+        last_fired: [Option<f64>; EVENT_KINDS],
+        held: [Vec<BookEvent>; EVENT_KINDS],
+    }
+
+    impl BookWatcher {
+        pub fn new(interval: f64) -> BookWatcher {
+            BookWatcher { interval, ..BookWatcher::default() }
+        }
+
+        /// Starts watching from the current state of the book, dropping the
+        /// sync and large level events it recorded so far.
+        pub fn reset(&mut self, book: &mut OrderBook) {
+            book.drain_sync_events();
+            book.drain_large_level_events();
+            self.best_bid = book.get_best_bid();
+            self.best_ask = book.get_best_ask();
+            self.spread = spread(book);
+            self.held = Default::default();
+        }
+
+        /// Events of the book since the previous call, unthrottled.
+        pub fn observe(&mut self, book: &mut OrderBook) -> Vec<BookEvent> {
+            let mut events = Vec::new();
+            for event in book.drain_sync_events() {
+                match event.state {
+                    SyncState::Gapped => events.push(BookEvent::SequenceGap { sequence: event.sequence }),
+                    SyncState::AwaitingSnapshot => events.push(BookEvent::ResyncNeeded { sequence: event.sequence }),
+                    SyncState::Synced | SyncState::Resyncing => {}
+                }
+            }
+            let (best_bid, best_ask) = (book.get_best_bid(), book.get_best_ask());
+            if best_bid != self.best_bid || best_ask != self.best_ask {
+                events.push(BookEvent::BestPrice { best_bid, best_ask });
+                self.best_bid = best_bid;
+                self.best_ask = best_ask;
+            }
+            let spread = spread(book);
+            if spread != self.spread {
+                if let Some(spread) = spread {
+                    events.push(BookEvent::Spread { spread });
+                }
+                self.spread = spread;
+            }
+            for level in book.drain_large_level_events() {
+                events.push(BookEvent::LargeLevel {
+                    side: level.side,
+                    price: level.price.to_f64().unwrap_or_default(),
+                    size: level.size.to_f64().unwrap_or_default(),
+                });
+            }
+            events
+        }
+
+        /// The events to fire at `now`, in milliseconds, out of `events` and
+        /// the ones held back earlier.
+        pub fn throttle(&mut self, events: Vec<BookEvent>, now: f64) -> Vec<BookEvent> {
+            if self.interval <= 0.0 {
+                return events;
+            }
+            for event in events {
+                let kind = event.kind();
+                let held = &mut self.held[kind as usize];
+                if kind.is_coalesced() {
+                    held.clear();
+                } else if held.len() >= MAX_HELD_EVENTS {
+                    held.remove(0);
+                }
+                held.push(event);
+            }
+            self.flush(now)
+        }
+
+        /// Held back events whose interval has passed at `now`.
+        pub fn flush(&mut self, now: f64) -> Vec<BookEvent> {
+            let mut due = Vec::new();
+            for kind in 0..EVENT_KINDS {
+                if self.held[kind].is_empty() {
+                    continue;
+                }
+                if let Some(last) = self.last_fired[kind] {
+                    if now - last < self.interval {
+                        continue;
+                    }
+                }
+                due.append(&mut self.held[kind]);
+                self.last_fired[kind] = Some(now);
+            }
+            due
+        }
+    }
+
+    fn spread(book: &OrderBook) -> Option<f64> {
+        if book.bids.is_empty() || book.asks.is_empty() {
+            None
+        } else {
+            Some(book.get_spread())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::subscriptions::{BookEvent, BookWatcher};
+    use crate::book::book::{OrderBook, OrderType};
+
+    fn create_book() -> OrderBook {
+        let mut book = OrderBook::new("instrument", 0);
+        book.add_level_str(OrderType::Bid, "100", "1", 1).unwrap();
+        book.add_level_str(OrderType::Ask, "101", "1", 2).unwrap();
+        book
+    }
+
+    #[test]
+    fn test_observe_book_events() {
+        let mut book = create_book();
+        book.set_large_level_size(Some(10.0));
+        let mut watcher = BookWatcher::new(0.0);
+        watcher.reset(&mut book);
+
+        book.add_level_str(OrderType::Bid, "99", "2", 3).unwrap();
+        assert!(watcher.observe(&mut book).is_empty());
+
+        book.add_level_str(OrderType::Bid, "100.5", "12", 4).unwrap();
+        let events = watcher.observe(&mut book);
+        assert_eq!(
+            events,
+            vec![
+                BookEvent::BestPrice { best_bid: 100.5, best_ask: 101.0 },
+                BookEvent::Spread { spread: 0.5 },
+                BookEvent::LargeLevel { side: OrderType::Bid, price: 100.5, size: 12.0 },
+            ]
+        );
+
+        // growing an already large level is not reported again
+        book.add_level_str(OrderType::Bid, "100.5", "15", 5).unwrap();
+        assert!(watcher.observe(&mut book).is_empty());
+
+        book.request_snapshot();
+        assert_eq!(watcher.observe(&mut book), vec![BookEvent::ResyncNeeded { sequence: 5 }]);
+    }
+
+    #[test]
+    fn test_throttle_book_events() {
+        let mut book = create_book();
+        let mut watcher = BookWatcher::new(100.0);
+        watcher.reset(&mut book);
+
+        book.add_level_str(OrderType::Bid, "100.25", "1", 3).unwrap();
+        let events = watcher.observe(&mut book);
+        assert_eq!(watcher.throttle(events, 1000.0).len(), 2);
+
+        book.add_level_str(OrderType::Bid, "100.5", "1", 4).unwrap();
+        let events = watcher.observe(&mut book);
+        assert!(watcher.throttle(events, 1050.0).is_empty());
+        book.add_level_str(OrderType::Bid, "100.75", "1", 5).unwrap();
+        let events = watcher.observe(&mut book);
+        assert!(watcher.throttle(events, 1080.0).is_empty());
+
+        assert!(watcher.flush(1090.0).is_empty());
+        assert_eq!(
+            watcher.flush(1100.0),
+            vec![
+                BookEvent::BestPrice { best_bid: 100.75, best_ask: 101.0 },
+                BookEvent::Spread { spread: 0.25 },
+            ]
+        );
+        assert!(watcher.flush(1300.0).is_empty());
+    }
+
+    #[test]
+    fn test_throttle_queues_discrete_events() {
+        let mut book = create_book();
+        book.set_large_level_size(Some(10.0));
+        let mut watcher = BookWatcher::new(100.0);
+        watcher.reset(&mut book);
+
+        book.add_level_str(OrderType::Bid, "99", "10", 3).unwrap();
+        let events = watcher.observe(&mut book);
+        assert_eq!(watcher.throttle(events, 1000.0).len(), 1);
+
+        book.add_level_str(OrderType::Bid, "98", "11", 4).unwrap();
+        book.add_level_str(OrderType::Ask, "102", "12", 5).unwrap();
+        let events = watcher.observe(&mut book);
+        assert!(watcher.throttle(events, 1050.0).is_empty());
+        assert_eq!(
+            watcher.flush(1100.0),
+            vec![
+                BookEvent::LargeLevel { side: OrderType::Bid, price: 98.0, size: 11.0 },
+                BookEvent::LargeLevel { side: OrderType::Ask, price: 102.0, size: 12.0 },
+            ]
+        );
+    }
+}
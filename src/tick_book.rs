@@ -0,0 +1,403 @@
+extern crate stock_messages;
+
+pub mod tick_book {
+    use crate::book::book::{Level, OrderBook, OrderType, Price, Size};
+    use crate::error::error::BookError;
+    use bigdecimal::BigDecimal;
+    use num_traits::cast::ToPrimitive;
+    use num_traits::identities::Zero;
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+    use std::ops::Mul;
+    use std::str::FromStr;
+    use stock_messages::stock_messages::{
+        BookInfo, LevelUpdate, PriceLevel, Side, SnapshotMessage, Type,
+    };
+
+    pub type Ticks = i64;
+    pub type Lots = u64;
+
+    /// Maximum distance from the nearest tick/lot (in ticks/lots) an `f64` input
+    /// may have before it is treated as off-grid instead of a float artifact.
+    const GRID_TOLERANCE: f64 = 1e-6;
+
+    /// Per-instrument price and quantity increments used to map decimal
+    /// prices/sizes onto integer ticks and lots.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct TickSpec {
+        pub tick_size: BigDecimal,
+        pub lot_size: BigDecimal,
+        tick_size_f64: f64,
+        lot_size_f64: f64,
+    }
+
+    impl TickSpec {
+        pub fn new(tick_size: &str, lot_size: &str) -> Option<TickSpec> {
+            let tick_size = BigDecimal::from_str(tick_size).ok()?;
+            let lot_size = BigDecimal::from_str(lot_size).ok()?;
+            if tick_size <= BigDecimal::zero() || lot_size <= BigDecimal::zero() {
+                return None;
+            }
+            Some(TickSpec {
+                tick_size_f64: tick_size.to_f64()?,
+                lot_size_f64: lot_size.to_f64()?,
+                tick_size,
+                lot_size,
+            })
+        }
+
+        // f64 Display prints the shortest representation that round trips, so
+        // 0.01 becomes "0.01" rather than its binary expansion.
+        pub fn from_f64(tick_size: f64, lot_size: f64) -> Option<TickSpec> {
+            if !tick_size.is_finite() || !lot_size.is_finite() {
+                return None;
+            }
+            TickSpec::new(&tick_size.to_string(), &lot_size.to_string())
+        }
+
+        pub fn price_to_ticks(&self, price: &BigDecimal) -> Option<Ticks> {
+            let ticks = price / &self.tick_size;
+            if !ticks.is_integer() {
+                return None;
+            }
+            ticks.to_i64()
+        }
+
+        pub fn size_to_lots(&self, size: &BigDecimal) -> Option<Lots> {
+            let lots = size / &self.lot_size;
+            if !lots.is_integer() {
+                return None;
+            }
+            lots.to_u64()
+        }
+
+        pub fn ticks_to_price(&self, ticks: Ticks) -> Price {
+            (&self.tick_size).mul(BigDecimal::from(ticks))
+        }
+
+        pub fn lots_to_size(&self, lots: Lots) -> Size {
+            (&self.lot_size).mul(BigDecimal::from(lots))
+        }
+
+        /// Allocation free conversion used on the update path. Prices that are
+        /// not within `GRID_TOLERANCE` of a tick are rejected.
+        pub fn price_f64_to_ticks(&self, price: f64) -> Option<Ticks> {
+            let ticks = price / self.tick_size_f64;
+            let rounded = ticks.round();
+            if !ticks.is_finite() || (ticks - rounded).abs() > GRID_TOLERANCE {
+                return None;
+            }
+            Some(rounded as Ticks)
+        }
+
+        pub fn size_f64_to_lots(&self, size: f64) -> Option<Lots> {
+            let lots = size / self.lot_size_f64;
+            let rounded = lots.round();
+            if !lots.is_finite() || rounded < 0.0 || (lots - rounded).abs() > GRID_TOLERANCE {
+                return None;
+            }
+            Some(rounded as Lots)
+        }
+
+        pub fn to_level(&self, ticks: Ticks, lots: Lots) -> Level {
+            let price = self.ticks_to_price(ticks);
+            let size = self.lots_to_size(lots);
+            Level {
+                value: (&price).mul(&size),
+                price,
+                size,
+            }
+        }
+
+        pub fn from_level(&self, level: &Level) -> Option<(Ticks, Lots)> {
+            Some((self.price_to_ticks(&level.price)?, self.size_to_lots(&level.size)?))
+        }
+
+        pub fn from_price_level(&self, level: &PriceLevel) -> Option<(Ticks, Lots)> {
+            Some((
+                self.price_f64_to_ticks(level.price)?,
+                self.size_f64_to_lots(level.total_size)?,
+            ))
+        }
+
+        pub fn to_price_level(&self, ticks: Ticks, lots: Lots) -> PriceLevel {
+            self.to_level(ticks, lots).into()
+        }
+    }
+
+    /// Order book keyed on integer ticks with sizes held as integer lots, so
+    /// updates never allocate and never accumulate float error.
+    #[derive(Debug, Clone)]
+    pub struct TickOrderBook {
+        pub instrument: String,
+        pub sequence: u64,
+        pub spec: TickSpec,
+        pub bids: BTreeMap<Ticks, Lots>,
+        pub asks: BTreeMap<Ticks, Lots>,
+
+        pub bids_total: Lots,
+        pub asks_total: Lots,
+        // in units of tick_size * lot_size
+        pub bids_value_total: i128,
+        pub asks_value_total: i128,
+    }
+
+    impl TickOrderBook {
+        pub fn new(instrument: &str, sequence: u64, spec: TickSpec) -> TickOrderBook {
+            TickOrderBook {
+                instrument: String::from(instrument),
+                sequence,
+                spec,
+                bids: BTreeMap::new(),
+                asks: BTreeMap::new(),
+                bids_total: 0,
+                asks_total: 0,
+                bids_value_total: 0,
+                asks_value_total: 0,
+            }
+        }
+
+        fn grid_level(&self, level: &PriceLevel) -> Result<(Ticks, Lots), BookError> {
+            let ticks = self.spec.price_f64_to_ticks(level.price).ok_or_else(|| BookError::InvalidPrice(level.price.to_string()))?;
+            let lots = self.spec.size_f64_to_lots(level.total_size).ok_or_else(|| BookError::InvalidSize(level.total_size.to_string()))?;
+            Ok((ticks, lots))
+        }
+
+        /// Fails with `InvalidPrice`/`InvalidSize` if a level is not on the tick/lot grid.
+        pub fn from_snapshot(snapshot: SnapshotMessage, spec: TickSpec) -> Result<TickOrderBook, BookError> {
+            let sequence: u64 = snapshot.source_sequence.try_into().unwrap_or(0u64);
+            let mut book = TickOrderBook::new(&snapshot.product_id, sequence, spec);
+            for level in snapshot.bids.iter() {
+                let (ticks, lots) = book.grid_level(level)?;
+                book.add_level(OrderType::Bid, ticks, lots, sequence);
+            }
+            for level in snapshot.asks.iter() {
+                let (ticks, lots) = book.grid_level(level)?;
+                book.add_level(OrderType::Ask, ticks, lots, sequence);
+            }
+            Ok(book)
+        }
+
+        pub fn update_level_message(&mut self, level_message: LevelUpdate) -> Result<(), BookError> {
+            let next_sequence = self.sequence + 1;
+            let received = level_message.sequence as u64;
+            if received < next_sequence {
+                return Err(BookError::StaleSequence { expected: next_sequence, received });
+            }
+            if received > next_sequence {
+                return Err(BookError::SequenceGap { expected: next_sequence, received });
+            }
+            let order_type = match Side::from_i32(level_message.side) {
+                Some(Side::Buy) => OrderType::Bid,
+                Some(_) => OrderType::Ask,
+                None => return Err(BookError::UnknownSide(level_message.side)),
+            };
+            let ticks = self
+                .spec
+                .price_f64_to_ticks(level_message.price)
+                .ok_or_else(|| BookError::InvalidPrice(level_message.price.to_string()))?;
+            let lots = self
+                .spec
+                .size_f64_to_lots(level_message.size)
+                .ok_or_else(|| BookError::InvalidSize(level_message.size.to_string()))?;
+            self.add_level(order_type, ticks, lots, received);
+            Ok(())
+        }
+
+        // Replaces the size at `ticks`; a size of zero lots removes the level.
+        pub fn add_level(&mut self, order_type: OrderType, ticks: Ticks, lots: Lots, sequence: u64) {
+            if lots == 0 {
+                return self.remove_level(order_type, ticks, sequence);
+            }
+            self.sequence = sequence;
+            let (levels, total, value_total) = match order_type {
+                OrderType::Bid => (&mut self.bids, &mut self.bids_total, &mut self.bids_value_total),
+                OrderType::Ask => (&mut self.asks, &mut self.asks_total, &mut self.asks_value_total),
+            };
+            let previous = levels.insert(ticks, lots).unwrap_or(0);
+            *total = *total - previous + lots;
+            *value_total += (ticks as i128) * (lots as i128 - previous as i128);
+        }
+
+        pub fn remove_level(&mut self, order_type: OrderType, ticks: Ticks, sequence: u64) {
+            self.sequence = sequence;
+            let (levels, total, value_total) = match order_type {
+                OrderType::Bid => (&mut self.bids, &mut self.bids_total, &mut self.bids_value_total),
+                OrderType::Ask => (&mut self.asks, &mut self.asks_total, &mut self.asks_value_total),
+            };
+            if let Some(previous) = levels.remove(&ticks) {
+                *total -= previous;
+                *value_total -= (ticks as i128) * (previous as i128);
+            }
+        }
+
+        pub fn get_best_bid(&self) -> Option<(Ticks, Lots)> {
+            self.bids.iter().next_back().map(|(ticks, lots)| (*ticks, *lots))
+        }
+
+        pub fn get_best_ask(&self) -> Option<(Ticks, Lots)> {
+            self.asks.iter().next().map(|(ticks, lots)| (*ticks, *lots))
+        }
+
+        pub fn get_spread_ticks(&self) -> Option<Ticks> {
+            Some(self.get_best_ask()?.0 - self.get_best_bid()?.0)
+        }
+
+        pub fn get_levels(&self, count: usize) -> (Vec<Level>, Vec<Level>) {
+            let bids = self
+                .bids
+                .iter()
+                .rev()
+                .take(count)
+                .map(|(ticks, lots)| self.spec.to_level(*ticks, *lots))
+                .collect::<Vec<Level>>();
+            let asks = self
+                .asks
+                .iter()
+                .take(count)
+                .map(|(ticks, lots)| self.spec.to_level(*ticks, *lots))
+                .collect::<Vec<Level>>();
+            (bids, asks)
+        }
+
+        pub fn bids_total_size(&self) -> Size {
+            self.spec.lots_to_size(self.bids_total)
+        }
+
+        pub fn asks_total_size(&self) -> Size {
+            self.spec.lots_to_size(self.asks_total)
+        }
+
+        pub fn bids_total_value(&self) -> BigDecimal {
+            (&self.spec.tick_size).mul(&self.spec.lot_size).mul(BigDecimal::from(self.bids_value_total))
+        }
+
+        pub fn asks_total_value(&self) -> BigDecimal {
+            (&self.spec.tick_size).mul(&self.spec.lot_size).mul(BigDecimal::from(self.asks_value_total))
+        }
+
+        /// Builds the equivalent `BigDecimal` keyed book, with exact prices and sizes.
+        pub fn to_order_book(&self) -> OrderBook {
+            let mut book = OrderBook::new(&self.instrument, self.sequence);
+            book.bids = self
+                .bids
+                .iter()
+                .map(|(ticks, lots)| (self.spec.ticks_to_price(*ticks), self.spec.to_level(*ticks, *lots)))
+                .collect();
+            book.asks = self
+                .asks
+                .iter()
+                .map(|(ticks, lots)| (self.spec.ticks_to_price(*ticks), self.spec.to_level(*ticks, *lots)))
+                .collect();
+            book.bids_total = self.bids_total_size();
+            book.asks_total = self.asks_total_size();
+            book.bids_value_total = self.bids_total_value();
+            book.asks_value_total = self.asks_total_value();
+            book.refresh_groupings();
+            book
+        }
+    }
+
+    impl Into<SnapshotMessage> for &TickOrderBook {
+        fn into(self) -> SnapshotMessage {
+            let info = BookInfo {
+                sequence: self.sequence as u32,
+                ask_total_size: self.asks_total_size().to_f64().unwrap_or(0f64),
+                ask_total_value: self.asks_total_value().to_f64().unwrap_or(0f64),
+                bid_total_size: self.bids_total_size().to_f64().unwrap_or(0f64),
+                bid_tota_value: self.bids_total_value().to_f64().unwrap_or(0f64),
+            };
+            SnapshotMessage {
+                trades: vec![],
+                r#type: Type::Snapshot.into(),
+                exchange: -1,
+                info,
+                product_id: String::from(&self.instrument),
+                bids: self
+                    .bids
+                    .iter()
+                    .map(|(ticks, lots)| self.spec.to_price_level(*ticks, *lots))
+                    .collect(),
+                asks: self
+                    .asks
+                    .iter()
+                    .map(|(ticks, lots)| self.spec.to_price_level(*ticks, *lots))
+                    .collect(),
+                source_sequence: self.sequence as i32,
+                takers: vec![],
+                time: 0u64,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tick_book::{TickOrderBook, TickSpec};
+    use crate::book::book::{OrderBook, OrderType};
+    use bigdecimal::BigDecimal;
+    use prost::Message;
+    use std::str::FromStr;
+    use stock_messages::stock_messages::SnapshotMessage;
+
+    fn btc_usdt_spec() -> TickSpec {
+        TickSpec::new("0.01", "0.000001").unwrap()
+    }
+
+    #[test]
+    fn test_f64_conversion_is_exact() {
+        let spec = btc_usdt_spec();
+        let ticks = spec.price_f64_to_ticks(9015.85).unwrap();
+        let lots = spec.size_f64_to_lots(0.027722).unwrap();
+        assert_eq!(ticks, 901585);
+        assert_eq!(lots, 27722);
+
+        let level = spec.to_level(ticks, lots);
+        assert_eq!(level.price, BigDecimal::from_str("9015.85").unwrap());
+        assert_eq!(level.size, BigDecimal::from_str("0.027722").unwrap());
+        assert_eq!(spec.from_level(&level), Some((ticks, lots)));
+
+        assert_eq!(spec.price_f64_to_ticks(9015.855), None);
+        assert_eq!(spec.size_f64_to_lots(-0.000001), None);
+    }
+
+    #[test]
+    fn test_update_totals() {
+        let mut book = TickOrderBook::new("instrument", 0, TickSpec::new("0.5", "1").unwrap());
+        book.add_level(OrderType::Bid, 200, 10, 1);
+        book.add_level(OrderType::Bid, 199, 5, 2);
+        book.add_level(OrderType::Bid, 200, 4, 3);
+        book.add_level(OrderType::Ask, 201, 7, 4);
+        assert_eq!(book.bids_total, 9);
+        assert_eq!(book.bids_value_total, 200 * 4 + 199 * 5);
+        assert_eq!(book.get_spread_ticks(), Some(1));
+
+        book.add_level(OrderType::Bid, 200, 0, 5);
+        book.remove_level(OrderType::Ask, 201, 6);
+        assert_eq!(book.bids_total, 5);
+        assert_eq!(book.asks_total, 0);
+        assert_eq!(book.asks_value_total, 0);
+        assert_eq!(book.get_best_bid(), Some((199, 5)));
+        assert_eq!(book.bids_total_value(), BigDecimal::from_str("497.5").unwrap());
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let bytes = std::fs::read("snapshots/Binance:BTC_USDT").unwrap();
+        let snapshot = SnapshotMessage::decode(bytes).unwrap();
+        let book = TickOrderBook::from_snapshot(snapshot.clone(), btc_usdt_spec()).unwrap();
+        assert_eq!(book.bids.len(), snapshot.bids.len());
+        assert_eq!(book.get_best_bid(), Some((901585, 27722)));
+        assert_eq!(book.get_best_ask(), Some((901778, 170818)));
+
+        let order_book: OrderBook = book.to_order_book();
+        let (bids, asks) = order_book.get_levels(1);
+        assert_eq!(bids[0].size, BigDecimal::from_str("0.027722").unwrap());
+        assert_eq!(asks[0].price, BigDecimal::from_str("9017.78").unwrap());
+
+        let encoded: SnapshotMessage = (&book).into();
+        let decoded = TickOrderBook::from_snapshot(encoded, btc_usdt_spec()).unwrap();
+        assert_eq!(decoded.bids, book.bids);
+        assert_eq!(decoded.asks, book.asks);
+    }
+}